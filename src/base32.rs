@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: © 2022 Austin Seipp
+
+//! Nix's own flavour of base32, as used for store path hashes and most of the
+//! hashes in narinfo files.
+//!
+//! This isn't RFC 4648 base32: the alphabet omits `e`, `o`, `u` and `t`, and
+//! the bytes are encoded starting from the *end* of the input.
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+use crate::error::ParseError;
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+const ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// The length of the base32 encoding of `n` bytes.
+pub fn encoded_len(n: usize) -> usize {
+    if n == 0 {
+        0
+    } else {
+        (n * 8 - 1) / 5 + 1
+    }
+}
+
+/// Encode some bytes in Nix base32.
+pub fn encode(bytes: &[u8]) -> String {
+    let len = encoded_len(bytes.len());
+    let mut out = String::with_capacity(len);
+    for n in (0..len).rev() {
+        let b = n * 5;
+        let (i, j) = (b / 8, b % 8);
+        let lo = (bytes[i] as u16) >> j;
+        let hi = match bytes.get(i + 1) {
+            Some(x) => (*x as u16) << (8 - j),
+            None => 0,
+        };
+        out.push(ALPHABET[((lo | hi) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// The number of bytes encoded by `n` characters of base32. Since each
+/// character is 5 bits, any bits left over at the end must be zero.
+pub fn decoded_len(n: usize) -> usize {
+    n * 5 / 8
+}
+
+/// Decode some Nix base32, failing on any character outside of the alphabet,
+/// or if the leftover bits past the last byte aren't zero.
+pub fn decode(s: &str) -> Result<Vec<u8>, ParseError> {
+    let len = decoded_len(s.len());
+    let mut out = vec![0u8; len];
+    for (pos, c) in s.bytes().enumerate() {
+        let digit = match ALPHABET.iter().position(|&a| a == c) {
+            Some(d) => d as u16,
+            None => return Err(ParseError::InvalidBase32 { pos, ch: c as char }),
+        };
+
+        // the last character holds the lowest bits
+        let b = (s.len() - pos - 1) * 5;
+        let (i, j) = (b / 8, b % 8);
+        let bits = digit << j;
+        match out.get_mut(i) {
+            Some(x) => *x |= bits as u8,
+            None if bits != 0 => return Err(ParseError::NonZeroPadding),
+            None => {}
+        }
+        match out.get_mut(i + 1) {
+            Some(x) => *x |= (bits >> 8) as u8,
+            None if bits >> 8 != 0 => return Err(ParseError::NonZeroPadding),
+            None => {}
+        }
+    }
+    Ok(out)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+    use crate::error::ParseError;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&[]), "");
+        assert_eq!(encode(&[0x1f]), "0z");
+        assert_eq!(encode(&[0; 20]), "00000000000000000000000000000000");
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("").unwrap(), Vec::<u8>::new());
+        assert_eq!(decode("0z").unwrap(), vec![0x1f]);
+
+        let bytes = decode("1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3").unwrap();
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(bytes[0], 0xa3);
+        assert_eq!(bytes[31], 0xd6);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let err = decode("1mpe").unwrap_err();
+        assert_eq!(err, ParseError::InvalidBase32 { pos: 3, ch: 'e' });
+        assert_eq!(
+            err.to_string(),
+            "invalid base32 character 'e' at position 3"
+        );
+        assert!(decode("0Z").is_err());
+
+        // 2 characters is 10 bits, so the top 2 must be zero for one byte
+        assert_eq!(decode("z0").unwrap_err(), ParseError::NonZeroPadding);
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        for _ in 0..1000 {
+            let bytes: [u8; 32] = rand::random();
+            let encoded = encode(&bytes);
+            assert_eq!(encoded.len(), 52);
+            assert_eq!(decode(&encoded).unwrap(), bytes);
+        }
+    }
+}
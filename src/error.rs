@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: © 2022 Austin Seipp
+
+//! Errors from parsing narinfos, for callers that need to tell the different
+//! kinds of failure apart rather than just print them.
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+use std::fmt;
+
+/// A way that a narinfo, or some part of one, can fail to parse.
+///
+/// Most functions in this crate return an [`anyhow::Error`]; when the failure
+/// was in parsing, it's one of these, and can be recovered with
+/// [`anyhow::Error::downcast_ref`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A line which isn't a `Key: value` field at all.
+    MissingColon { line: String },
+    /// A line with whitespace around its field name or value, rather than
+    /// exactly `Key: value` like Nix writes.
+    BadSpacing { line: String },
+    /// A field other than `Sig` which occurs more than once.
+    DuplicateField { name: String },
+    /// A field which every narinfo must have is missing.
+    MissingField { name: String },
+    /// A field which must be a number isn't one.
+    BadNumber { field: String, value: String },
+    /// A `Sig` which isn't of the form `host:signature`.
+    BadSig { sig: String },
+    /// A hash using an algorithm other than the ones Nix signs with.
+    BadHashAlgo { algo: String },
+    /// A hash which is the wrong length for its algorithm. Both lengths
+    /// include the `algo:` prefix.
+    BadHashLength { expected: usize, got: usize },
+    /// A character which isn't part of Nix's base32 alphabet.
+    InvalidBase32 { pos: usize, ch: char },
+    /// Base32 with leftover bits past the last byte that aren't zero, so it
+    /// isn't the canonical encoding of anything.
+    NonZeroPadding,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingColon { line } => write!(f, "line without a field name: {}", line),
+            ParseError::BadSpacing { line } => {
+                write!(f, "line isn't exactly 'Key: value': {:?}", line)
+            }
+            ParseError::DuplicateField { name } => write!(f, "duplicate field: {}", name),
+            ParseError::MissingField { name } => write!(f, "missing required field: {}", name),
+            ParseError::BadNumber { field, value } => {
+                write!(f, "{} is not a number: {}", field, value)
+            }
+            ParseError::BadSig { sig } => write!(f, "invalid signature: {}", sig),
+            ParseError::BadHashAlgo { algo } => {
+                write!(f, "hash must be sha1, sha256 or sha512, not {}", algo)
+            }
+            ParseError::BadHashLength { expected, got } => {
+                write!(f, "invalid hash length (not {}): {}", expected, got)
+            }
+            ParseError::InvalidBase32 { pos, ch } => {
+                write!(f, "invalid base32 character {:?} at position {}", ch, pos)
+            }
+            ParseError::NonZeroPadding => write!(f, "invalid base32: non-zero padding bits"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
@@ -0,0 +1,17 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: © 2022 Austin Seipp
+
+//! Tools for working with Nix `narinfo` files: converting them to JSON, and
+//! computing, signing and verifying their fingerprints, without needing Nix
+//! itself. The `narinfo-tools` binary is a thin wrapper around this.
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub mod base32;
+pub mod error;
+pub mod narinfo;
+
+pub use error::ParseError;
+pub use narinfo::{
+    narinfo_to_json, sign_narinfo, sk_to_keypair, sk_to_pk, Fingerprint, Keys, Store,
+};
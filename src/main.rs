@@ -3,68 +3,1891 @@
 
 // ---------------------------------------------------------------------------------------------------------------------
 
-use std::{env, io::Read};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use anyhow::Result;
-use narinfo::{sk_to_keypair, sk_to_pk};
-
-mod narinfo;
+use anyhow::{bail, Result};
+use narinfo_tools::narinfo;
+use narinfo_tools::{sk_to_keypair, sk_to_pk};
 
 extern crate wee_alloc;
 
 // ---------------------------------------------------------------------------------------------------------------------
 
+/// A flag, along with what it does.
+type Flag = (&'static str, &'static str);
+
+/// Every mode, along with a short description of it and the flags it takes.
+const MODES: &[(&str, &str, &[Flag])] = &[
+    (
+        "json",
+        "Convert a narinfo to JSON",
+        &[
+            ("--abs-refs", "Make the References absolute store paths"),
+            (
+                "--refs-detailed",
+                "Give each reference's hash, name and path",
+            ),
+            ("--content-addressed", "Add whether there's a CA field"),
+            (
+                "--content-addressed-json",
+                "Print the JSON in its canonical form",
+            ),
+            ("--no-special-cases", "Copy every field's text exactly"),
+            ("--lenient", "Accept a Sig without a host: prefix"),
+            ("--omit-empty", "Leave out fields with empty values"),
+            ("--strict", "Reject fields which appear more than once"),
+            (
+                "--resolve-ref-urls <dir>",
+                "Look up each reference's URL in a cache dir",
+            ),
+            (
+                "--group-sigs-by-validity <pks>",
+                "Split the signatures by whether these keys trust them",
+            ),
+            (
+                "--verify-and-json",
+                "Check the signatures against NIX_TRUSTED_PUBLIC_KEYS too",
+            ),
+        ],
+    ),
+    (
+        "json-batch",
+        "Convert narinfos separated by blank lines to NDJSON",
+        &[
+            ("--abs-refs", "Make the References absolute store paths"),
+            (
+                "--refs-detailed",
+                "Give each reference's hash, name and path",
+            ),
+            ("--content-addressed", "Add whether there's a CA field"),
+            ("--no-special-cases", "Copy every field's text exactly"),
+            ("--lenient", "Accept a Sig without a host: prefix"),
+            ("--omit-empty", "Leave out fields with empty values"),
+            ("--strict", "Reject fields which appear more than once"),
+            (
+                "--group-sigs-by-validity <pks>",
+                "Split the signatures by whether these keys trust them",
+            ),
+            (
+                "--verify-and-json",
+                "Check the signatures against NIX_TRUSTED_PUBLIC_KEYS too",
+            ),
+        ],
+    ),
+    (
+        "from-json",
+        "Convert JSON from the json mode back to a narinfo",
+        &[("--synthesize-url", "Make up a URL if there isn't one")],
+    ),
+    (
+        "sign",
+        "Sign a narinfo with NIX_SIGNING_KEY",
+        &[
+            (
+                "--store-dir-from-path",
+                "Take the store dir from the StorePath",
+            ),
+            ("--key-file <file>", "Read the secret key from a file"),
+            (
+                "--ephemeral <name>",
+                "Sign with a new key, printing its public key",
+            ),
+            ("--json", "Print the signature as JSON"),
+            ("--in-place", "Add the signature to each file given"),
+            ("--keep-sigs", "Keep the signatures already there"),
+            ("--parallel <n>", "How many files to work on at once"),
+        ],
+    ),
+    (
+        "fingerprint",
+        "Print the string that a narinfo's signatures are made over",
+        &[("--verbose", "Explain where each part comes from")],
+    ),
+    (
+        "signable-fields",
+        "Print only the fields of a narinfo that get signed",
+        &[],
+    ),
+    (
+        "query",
+        "Print the value of a single field of a narinfo",
+        &[],
+    ),
+    (
+        "fields",
+        "List which fields a narinfo has, and which required ones are missing",
+        &[("--json", "Print the fields as JSON")],
+    ),
+    (
+        "synthesize",
+        "Create a narinfo from command line flags",
+        &[
+            ("--compression <c>", "The Compression field"),
+            (
+                "--compression-default <c>",
+                "The Compression if --compression isn't given",
+            ),
+            ("--store-path <path>", "The StorePath field"),
+            ("--url <url>", "The URL field"),
+            ("--file-hash <hash>", "The FileHash field"),
+            ("--file-size <n>", "The FileSize field"),
+            ("--nar-hash <hash>", "The NarHash field"),
+            ("--nar-size <n>", "The NarSize field"),
+            ("--references <refs>", "The References, separated by spaces"),
+            ("--deriver <drv>", "The Deriver field"),
+            (
+                "--sig <host:sig>",
+                "A signature, which may be given more than once",
+            ),
+        ],
+    ),
+    (
+        "import-nix-json",
+        "Convert `nix path-info --json` output to narinfos",
+        &[],
+    ),
+    (
+        "verify",
+        "Check a narinfo's signatures against NIX_TRUSTED_PUBLIC_KEYS",
+        &[
+            ("--count-only", "Only print how many signatures are valid"),
+            ("--show-key", "Print which key made each signature"),
+            (
+                "--allow-unsigned",
+                "Succeed even if there aren't any signatures",
+            ),
+            (
+                "--accept-retired <file>",
+                "Also trust the keys retired in this file",
+            ),
+            ("--infer-store-dir", "Take the store dir from the StorePath"),
+            (
+                "--trust-embedded-keys",
+                "Also trust any '# trusted-keys:' lines",
+            ),
+        ],
+    ),
+    (
+        "full-verify",
+        "Check a NAR against a narinfo, and the narinfo's signatures",
+        &[
+            (
+                "--allow-unsigned",
+                "Succeed even if there aren't any signatures",
+            ),
+            (
+                "--trust-embedded-keys",
+                "Also trust any '# trusted-keys:' lines",
+            ),
+        ],
+    ),
+    (
+        "hash-nar",
+        "Compute the NarHash and NarSize of a NAR read from stdin",
+        &[],
+    ),
+    (
+        "verify-batch",
+        "Verify the signatures of many narinfo files",
+        &[
+            (
+                "--allow-unsigned",
+                "Succeed even if there aren't any signatures",
+            ),
+            (
+                "--trust-embedded-keys",
+                "Also trust any '# trusted-keys:' lines",
+            ),
+            ("--parallel <n>", "How many files to work on at once"),
+        ],
+    ),
+    (
+        "normalize-hash",
+        "Rewrite base16 hashes in a narinfo to base32",
+        &[(
+            "--keep-sigs",
+            "Keep the signatures, which may no longer match",
+        )],
+    ),
+    (
+        "rewrite-store-dir",
+        "Move a narinfo to a different store dir",
+        &[
+            ("--from <dir>", "The store dir to move from"),
+            ("--to <dir>", "The store dir to move to"),
+            ("--sign", "Sign the result with NIX_SIGNING_KEY"),
+            (
+                "--key-file <file>",
+                "Sign the result with the key in a file",
+            ),
+        ],
+    ),
+    (
+        "validate",
+        "Check a narinfo for errors and warnings",
+        &[
+            (
+                "--reference-closure-check",
+                "Warn if the path doesn't refer to itself",
+            ),
+            (
+                "--verify-store-path-hash",
+                "Check a CA StorePath against its hash",
+            ),
+        ],
+    ),
+    ("diff", "Compare the fields of two narinfo files", &[]),
+    (
+        "scan",
+        "Validate many narinfo files",
+        &[
+            ("--only-valid", "Only print the files without errors"),
+            ("--input-dir <dir>", "Scan the narinfos in a dir"),
+            ("--recursive", "Scan subdirs too"),
+            ("--max-depth <n>", "How deep to go with --recursive"),
+            ("--parallel <n>", "How many files to work on at once"),
+        ],
+    ),
+    (
+        "store-path-hash",
+        "Compute the hash part of a store path",
+        &[
+            (
+                "--type <type>",
+                "The kind of store path, like source or output:out",
+            ),
+            ("--hash <hash>", "The hash of the contents"),
+            ("--name <name>", "The name of the store path"),
+        ],
+    ),
+    (
+        "make-cache-info",
+        "Create a nix-cache-info file for a cache",
+        &[
+            ("--priority <n>", "The cache's priority"),
+            ("--want-mass-query", "Let Nix query many paths at once"),
+        ],
+    ),
+    (
+        "compare-keys",
+        "Check whether two public keys are the same key",
+        &[],
+    ),
+    (
+        "gen-key",
+        "Generate a new signing keypair",
+        &[
+            (
+                "--emit-keys-file <base>",
+                "Write the keys to <base>.sec and <base>.pub",
+            ),
+            ("--force", "Overwrite the key files if they exist"),
+            ("--seed <hex>", "Derive the key from a 32 byte seed"),
+        ],
+    ),
+    ("sk-to-pk", "Print the public key for NIX_SIGNING_KEY", &[]),
+    (
+        "key-digest",
+        "Describe a signing key as JSON, without revealing it",
+        &[("--key-file <file>", "Read the secret key from a file")],
+    ),
+];
+
+/// The modes which read the signing key from NIX_SIGNING_KEY.
+const SIGNING_MODES: &[&str] = &["sign", "rewrite-store-dir", "sk-to-pk", "key-digest"];
+
+/// The flags which work with every mode, along with what they do.
+const GLOBAL_FLAGS: &[Flag] = &[
+    (
+        "--store-dir <dir>",
+        "The store dir, instead of NIX_STORE_DIR",
+    ),
+    (
+        "--store-env <var>",
+        "Read the store dir from another variable",
+    ),
+    (
+        "--key-env <var>",
+        "Read the signing key from another variable",
+    ),
+    (
+        "--chunk-size <n>",
+        "The size of the buffer to read input with",
+    ),
+    ("--lossy", "Replace invalid UTF-8 in the input"),
+    (
+        "--max-references <n>",
+        "The most References a narinfo may have",
+    ),
+    (
+        "--max-line-length <n>",
+        "The longest a line of a narinfo may be",
+    ),
+    (
+        "--strict-colon",
+        "Reject fields not written as 'Key: value'",
+    ),
+    (
+        "--require-fields <a,b,..>",
+        "Reject a narinfo missing any of these fields",
+    ),
+    (
+        "--fail-on-warning",
+        "Exit with an error if there were warnings",
+    ),
+    (
+        "--help",
+        "Print this help, or a mode's with `<mode> --help`",
+    ),
+    ("--version", "Print the version"),
+];
+
+/// One line of a help listing, keeping the descriptions lined up, but
+/// never running a long flag into its description.
+fn help_line(name: &str, description: &str) -> String {
+    format!("  {:<22}  {}\n", name, description)
+}
+
+/// The help for `--help`, listing every mode and the global flags.
+fn usage() -> String {
+    let mut out = format!(
+        "{} {}\n{}\n\nUsage: {} [flags] <mode> [mode flags] [file]\n\nModes:\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        env!("CARGO_PKG_DESCRIPTION"),
+        env!("CARGO_PKG_NAME"),
+    );
+    for (name, description, _) in MODES {
+        out += &help_line(name, description);
+    }
+    out += "\nFlags:\n";
+    for (flag, description) in GLOBAL_FLAGS {
+        out += &help_line(flag, description);
+    }
+    out
+}
+
+/// The help for `<mode> --help`, if there's such a mode.
+fn mode_usage(mode: &str) -> Option<String> {
+    let (name, description, mode_flags) = MODES.iter().find(|(name, _, _)| *name == mode)?;
+    let mut out = format!(
+        "{}\n\nUsage: {} [flags] {} [mode flags] [file]\n\n\
+         The input is read from stdin, unless a mode takes a file instead.\n",
+        description,
+        env!("CARGO_PKG_NAME"),
+        name,
+    );
+    if !mode_flags.is_empty() {
+        out += "\nMode flags:\n";
+        for (flag, description) in mode_flags.iter() {
+            out += &help_line(flag, description);
+        }
+    }
+    // the signing key is only read by the modes that sign, and --version
+    // ignores the mode entirely
+    out += "\nFlags:\n";
+    for (flag, description) in GLOBAL_FLAGS {
+        let applies = match *flag {
+            "--version" => false,
+            "--key-env <var>" => SIGNING_MODES.contains(name),
+            _ => true,
+        };
+        if applies {
+            out += &help_line(flag, description);
+        }
+    }
+    Some(out)
+}
+
+/// List the names of every mode, one per line, or as a JSON array of
+/// objects with descriptions.
+fn list_modes(json: bool) -> String {
+    if json {
+        let modes: Vec<serde_json::Value> = MODES
+            .iter()
+            .map(|(name, description, _)| {
+                serde_json::json!({ "name": name, "description": description })
+            })
+            .collect();
+        format!("{}\n", serde_json::Value::Array(modes))
+    } else {
+        MODES
+            .iter()
+            .map(|(name, _, _)| format!("{}\n", name))
+            .collect()
+    }
+}
+
+/// Write a nix-cache-info file. Nix assumes a priority of 50 when there
+/// isn't one, so it's only written when asked for.
+fn cache_info(store_dir: &str, priority: Option<u64>, want_mass_query: bool) -> String {
+    let mut out = format!("StoreDir: {}\n", store_dir);
+    out += &format!("WantMassQuery: {}\n", if want_mass_query { 1 } else { 0 });
+    if let Some(p) = priority {
+        out += &format!("Priority: {}\n", p);
+    }
+    out
+}
+
+/// The narinfo that `bench` signs: yosys-0.15 from cache.nixos.org, which has
+/// a realistic number of references.
+const BENCH_NARINFO: &str = "StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
+Compression: xz
+FileHash: sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds
+FileSize: 3542408
+NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
+NarSize: 17680416
+References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib 20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2 9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11 ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11 dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16 dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15 fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3 hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10 klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04 q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117 sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08
+Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv
+";
+
+/// Sign [`BENCH_NARINFO`] `count` times with a throwaway key, returning how
+/// long it took and how many signatures that is per second. Each one goes
+/// through [`narinfo::sign_narinfo`], so the parsing is counted too.
+fn bench(count: usize) -> Result<(std::time::Duration, f64)> {
+    let store = narinfo::Store::new("/nix/store")?;
+    let keys = narinfo::Keys::generate("bench");
+    let start = std::time::Instant::now();
+    for _ in 0..count {
+        narinfo::sign_narinfo(&store, &keys, BENCH_NARINFO)?;
+    }
+    let elapsed = start.elapsed();
+    // a handful of signatures can take less time than the clock resolves
+    let secs = elapsed.as_secs_f64().max(1e-9);
+    Ok((elapsed, count as f64 / secs))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} [json] < ...", args[0]);
+    let mut flags = Flags::new(&args[1..]);
+    let fail_on_warning = flags.switch("--fail-on-warning");
+    let globals = Globals {
+        chunk_size: match flags.value("--chunk-size")? {
+            Some(n) => match n.parse()? {
+                0 => bail!("--chunk-size must be greater than zero"),
+                n => n,
+            },
+            None => narinfo::DEFAULT_CHUNK_SIZE,
+        },
+        lossy: flags.switch("--lossy"),
+        max_references: match flags.value("--max-references")? {
+            Some(n) => n.parse()?,
+            None => narinfo::DEFAULT_MAX_REFERENCES,
+        },
+        max_line_length: match flags.value("--max-line-length")? {
+            Some(n) => Some(n.parse()?),
+            None => None,
+        },
+        key_env: flags
+            .value("--key-env")?
+            .unwrap_or_else(|| "NIX_SIGNING_KEY".into()),
+        store_env: flags
+            .value("--store-env")?
+            .unwrap_or_else(|| "NIX_STORE_DIR".into()),
+        store_dir: flags.value("--store-dir")?,
+        strict_colon: flags.switch("--strict-colon"),
+        required_fields: flags
+            .value("--require-fields")?
+            .map(|v| {
+                v.split(',')
+                    .map(|k| k.trim())
+                    .filter(|k| !k.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+
+    if flags.switch("--version") {
+        flags.finish()?;
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if flags.switch("--help") {
+        match flags.mode() {
+            Some(mode) => match mode_usage(&mode) {
+                Some(usage) => print!("{}", usage),
+                None => bail!("Unknown mode: {}", mode),
+            },
+            None => print!("{}", usage()),
+        }
+        return Ok(());
+    }
+
+    // hidden, for shell completions and wrapper scripts
+    if flags.switch("--list-modes") {
+        let json = flags.switch("--json");
+        flags.finish()?;
+        print!("{}", list_modes(json));
+        return Ok(());
+    }
+
+    let mode = match flags.mode() {
+        Some(mode) => mode,
+        None => {
+            eprint!("{}", usage());
+            std::process::exit(1);
+        }
+    };
+
+    let mut warnings = narinfo::Warnings::new();
+    let success = run(&mode, flags, &globals, &mut warnings);
+    warnings.finish(fail_on_warning)?;
+    if !success? {
         std::process::exit(1);
     }
 
-    let mode = &args[1];
-    match mode.as_str() {
-        "json" => {
-            let mut content = String::new();
-            std::io::stdin().read_to_string(&mut content)?;
+    Ok(())
+}
+
+/// Run a single mode, returning whether it succeeded.
+fn run(
+    mode: &str,
+    mut flags: Flags,
+    globals: &Globals,
+    warnings: &mut narinfo::Warnings,
+) -> Result<bool> {
+    match mode {
+        "json" | "json-batch" => {
+            let abs_refs = flags.switch("--abs-refs");
+            let refs_detailed = flags.switch("--refs-detailed");
+            let content_addressed = flags.switch("--content-addressed");
+            // these two only make sense for a single narinfo, so json-batch
+            // rejects them like any other flag it doesn't know
+            let content_addressed_json = mode == "json" && flags.switch("--content-addressed-json");
+            let no_special_cases = flags.switch("--no-special-cases");
+            let lenient = flags.switch("--lenient");
+            let omit_empty = flags.switch("--omit-empty");
+            let strict = flags.switch("--strict");
+            let resolve_ref_urls = match mode {
+                "json" => flags.value("--resolve-ref-urls")?,
+                _ => None,
+            };
+            let group_sigs = flags
+                .value("--group-sigs-by-validity")?
+                .map(|pks| narinfo::TrustStore::parse(&pks))
+                .transpose()?;
+            let verification = if flags.switch("--verify-and-json") {
+                Some(narinfo::TrustStore::parse(&env::var(
+                    "NIX_TRUSTED_PUBLIC_KEYS",
+                )?)?)
+            } else {
+                None
+            };
+            let files = flags.finish()?;
+
+            // json-batch streams its input below, rather than reading it all
+            let content = match mode {
+                "json" => globals.read_input(&files)?,
+                _ => String::new(),
+            };
+            let ref_urls = resolve_ref_urls
+                .map(|dir| ref_urls(Path::new(&dir), &content))
+                .transpose()?;
+
+            // the detailed references always include the full path, and
+            // checking signatures needs a fingerprint
+            let check_sigs = group_sigs.is_some() || verification.is_some();
+            let store_dir = if abs_refs || refs_detailed || check_sigs {
+                Some(narinfo::Store::new(&globals.store_dir()?)?)
+            } else {
+                None
+            };
+            let opts = narinfo::JsonOptions {
+                abs_refs: store_dir.as_ref().filter(|_| abs_refs || refs_detailed),
+                refs_detailed,
+                ref_urls: ref_urls.as_ref(),
+                content_addressed,
+                group_sigs: store_dir.as_ref().zip(group_sigs.as_ref()),
+                no_special_cases,
+                lenient,
+                verification: store_dir.as_ref().zip(verification.as_ref()),
+                omit_empty,
+                strict,
+            };
 
-            let mut out = String::new();
-            narinfo::narinfo_to_json(content, &mut out);
-            println!("{}", out);
+            if mode == "json-batch" {
+                let input: Box<dyn std::io::BufRead> = match files.as_slice() {
+                    [] => Box::new(std::io::stdin().lock()),
+                    [file] => {
+                        let f = std::fs::File::open(file)?;
+                        Box::new(std::io::BufReader::with_capacity(globals.chunk_size, f))
+                    }
+                    _ => bail!("expected at most one file, but got {}", files.len()),
+                };
+                // each record gets the same checks as a single narinfo would
+                narinfo::narinfos_to_ndjson(
+                    input,
+                    &mut std::io::stdout().lock(),
+                    &opts,
+                    globals.lossy,
+                    globals.max_line_length,
+                    |record| globals.check(record),
+                    warnings,
+                )?;
+                return Ok(true);
+            }
+
+            if content_addressed_json {
+                println!(
+                    "{}",
+                    narinfo::narinfo_to_canonical_json(&content, warnings)?
+                );
+                return Ok(true);
+            }
+
+            let mut out = std::io::stdout().lock();
+            narinfo::narinfo_to_json_writer(&content, &mut out, &opts, warnings)?;
+        }
+        "from-json" => {
+            let synthesize_url = flags.switch("--synthesize-url");
+            flags.finish()?;
+            let content = globals.read_stdin()?;
+            print!(
+                "{}",
+                narinfo::json_to_narinfo_with(&content, synthesize_url)?
+            );
         }
         "sign" => {
-            let store_dir = narinfo::Store::new(&env::var("NIX_STORE_DIR")?)?;
-            let sk = env::var("NIX_SIGNING_KEY")?;
-            let keys = sk_to_keypair(&sk)?;
+            let infer_store_dir = flags.switch("--store-dir-from-path");
+            let key_file = flags.value("--key-file")?;
+            let ephemeral = flags.value("--ephemeral")?;
+            let json = flags.switch("--json");
+            let in_place = flags.switch("--in-place");
+            let keep_sigs = flags.switch("--keep-sigs");
+            let jobs = parallel_jobs(&mut flags)?;
+            let files = flags.finish()?;
 
-            let mut content = String::new();
-            std::io::stdin().read_to_string(&mut content)?;
+            let mut all_keys = match (&ephemeral, key_file) {
+                (Some(name), _) => {
+                    // print the key somewhere it won't get mixed up with the
+                    // narinfo, so that a test harness can go and trust it
+                    let keys = narinfo::Keys::generate(name);
+                    eprintln!("{}", keys.public_key());
+                    vec![keys]
+                }
+                (None, Some(path)) => vec![narinfo::load_secret_key_file(Path::new(&path))?],
+                // several keys may be given at once, separated by whitespace
+                (None, None) => globals
+                    .signing_key()?
+                    .split_whitespace()
+                    .map(sk_to_keypair)
+                    .collect::<Result<_>>()?,
+            };
+            if all_keys.len() > 1 && (in_place || json) {
+                bail!("signing with more than one key doesn't work with --in-place or --json");
+            }
+            if all_keys.is_empty() {
+                bail!("{} doesn't contain any keys", globals.key_env);
+            }
+
+            if in_place {
+                if files.is_empty() {
+                    bail!("--in-place requires at least one file");
+                }
+                let (keys, globals) = (Arc::new(all_keys.remove(0)), globals.clone());
+                let signed = map_files(&files, jobs, move |file| {
+                    let content = globals.read_file(file)?;
+                    let store_dir = store_for(&content, infer_store_dir, &globals)?;
+                    narinfo::sign_in_place(Path::new(file), &content, &store_dir, &keys)
+                });
+                for (file, signed) in files.iter().zip(signed) {
+                    if !signed? {
+                        eprintln!("{}: already signed, skipping", file);
+                    }
+                }
+                return Ok(true);
+            } else if files.len() > 1 {
+                bail!("signing more than one file requires --in-place");
+            }
+
+            // whatever gets printed should have LF line endings, even if
+            // it's already signed and passed through as-is
+            let content = globals.read_input(&files)?.replace("\r\n", "\n");
             let body = content.trim();
+            let store_dir = store_for(body, infer_store_dir, globals)?;
+
+            if all_keys.len() > 1 {
+                // more than one key is always alongside any existing sigs
+                let sigs = narinfo::sign_narinfo_with_keys(&store_dir, &all_keys, body)?;
+                print!("{}", narinfo::replace_signatures(body, &sigs));
+                return Ok(true);
+            }
+            let keys = all_keys.remove(0);
+
+            if json {
+                // the caller is handling the signature themselves, so they
+                // get one regardless of what's in the body already
+                println!(
+                    "{}",
+                    narinfo::sign_narinfo_to_json(&store_dir, &keys, body)?
+                );
+                return Ok(true);
+            }
 
-            if body.lines().any(|l| l.starts_with("Sig:")) {
+            if keep_sigs {
+                let sigs = narinfo::sign_narinfo_keeping_sigs(&store_dir, &keys, body)?;
+                print!("{}", narinfo::replace_signatures(body, &sigs));
+                return Ok(true);
+            }
+
+            if ephemeral.is_none() && body.lines().any(|l| l.starts_with("Sig:")) {
                 // if a signature already exists, don't sign again. nix itself
                 // doesn't actually support multiple signatures, it seems, so
                 // presumably this signature is from another trusted key, i.e.
                 // it might be from cache.nixos.org
                 println!("{}", body);
-                return Ok(());
+                return Ok(true);
             }
 
             let sig = narinfo::sign_narinfo(&store_dir, &keys, body)?;
-            let result = format!("{}\nSig: {}", body, sig);
-            println!("{}", result);
+            println!("{}", narinfo::append_signature(body, &sig));
+        }
+        "fingerprint" => {
+            let verbose = flags.switch("--verbose");
+            let files = flags.finish()?;
+            let store_dir = narinfo::Store::new(&globals.store_dir()?)?;
+
+            let content = globals.read_input(&files)?;
+            if verbose {
+                print!("{}", narinfo::explain_fingerprint(&store_dir, &content)?);
+            } else {
+                println!("{}", narinfo::fingerprint_narinfo(&store_dir, &content)?);
+            }
+        }
+        "signable-fields" => {
+            flags.finish()?;
+            let content = globals.read_stdin()?;
+            print!("{}", narinfo::signable_fields(&content)?);
+        }
+        "query" => {
+            let field = match flags.finish()?.as_slice() {
+                [field] => field.clone(),
+                _ => bail!("query requires exactly one field name"),
+            };
+            let content = globals.read_stdin()?;
+
+            let values = narinfo::query_field(&content, &field);
+            for v in values.iter() {
+                println!("{}", v);
+            }
+            return Ok(!values.is_empty());
+        }
+        "fields" => {
+            let json = flags.switch("--json");
+            flags.finish()?;
+            let content = globals.read_stdin()?;
+
+            let presence = narinfo::field_presence(&content);
+            let missing: Vec<&str> = presence
+                .iter()
+                .filter(|(k, present)| !present && narinfo::REQUIRED_FIELDS.contains(k))
+                .map(|(k, _)| *k)
+                .collect();
+            if json {
+                let fields: serde_json::Map<String, serde_json::Value> = presence
+                    .iter()
+                    .map(|(k, present)| (k.to_string(), (*present).into()))
+                    .collect();
+                let report = serde_json::json!({ "fields": fields, "missingRequired": missing });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                // a checklist for people, like warnings, rather than output
+                for (k, present) in presence.iter() {
+                    let mark = if *present { "x" } else { " " };
+                    let note = if missing.contains(k) {
+                        " (required)"
+                    } else {
+                        ""
+                    };
+                    eprintln!("[{}] {}{}", mark, k, note);
+                }
+            }
+            return Ok(missing.is_empty());
+        }
+        "synthesize" => {
+            let mut builder = narinfo::NarInfoBuilder::new().compression_default(
+                flags
+                    .value("--compression-default")?
+                    .unwrap_or_else(|| "none".into()),
+            );
+            if let Some(v) = flags.value("--compression")? {
+                builder = builder.compression(v);
+            }
+            if let Some(v) = flags.value("--store-path")? {
+                builder = builder.store_path(v);
+            }
+            if let Some(v) = flags.value("--url")? {
+                builder = builder.url(v);
+            }
+            if let Some(v) = flags.value("--file-hash")? {
+                builder = builder.file_hash(v);
+            }
+            if let Some(v) = flags.value("--file-size")? {
+                builder = builder.file_size(v.parse()?);
+            }
+            if let Some(v) = flags.value("--nar-hash")? {
+                builder = builder.nar_hash(v);
+            }
+            if let Some(v) = flags.value("--nar-size")? {
+                builder = builder.nar_size(v.parse()?);
+            }
+            if let Some(v) = flags.value("--references")? {
+                for r in v.split_whitespace() {
+                    builder = builder.reference(r);
+                }
+            }
+            if let Some(v) = flags.value("--deriver")? {
+                builder = builder.deriver(v);
+            }
+            for v in flags.values("--sig")? {
+                match v.split_once(':') {
+                    Some((host, sig)) => builder = builder.sig(host, sig),
+                    None => bail!("invalid signature, expected host:sig: {}", v),
+                }
+            }
+            flags.finish()?;
+
+            print!("{}", builder.build()?);
+        }
+        "import-nix-json" => {
+            flags.finish()?;
+            let content = globals.read_stdin()?;
+
+            let narinfos = narinfo::path_info_to_narinfos(&content)?;
+            for (i, ni) in narinfos.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print!("{}", ni);
+            }
+        }
+        "verify" => {
+            let count_only = flags.switch("--count-only");
+            let show_key = flags.switch("--show-key");
+            let allow_unsigned = flags.switch("--allow-unsigned");
+            let retired = flags.value("--accept-retired")?;
+            let infer_store_dir = flags.switch("--infer-store-dir");
+            let trust_embedded = flags.switch("--trust-embedded-keys");
+            let files = flags.finish()?;
+
+            let content = globals.read_input(&files)?;
+            let store_dir = store_for(&content, infer_store_dir, globals)?;
+
+            let trusted = trusted_keys(retired)?;
+            let trusted = keys_for_narinfo(&trusted, &content, trust_embedded)?;
+            let checks = narinfo::verify_narinfo(&store_dir, &trusted, &content, warnings)?;
+            let (valid, invalid) = narinfo::tally(&checks);
+            if count_only {
+                println!("valid: {}", valid);
+                println!("invalid: {}", invalid);
+            } else {
+                for c in checks.iter() {
+                    match c.key_base64() {
+                        Some(key) if show_key => println!("{}: {} ({})", c.host, c.status, key),
+                        _ => println!("{}: {}", c.host, c.status),
+                    }
+                }
+            }
+
+            if !narinfo::verified(&checks, allow_unsigned)? {
+                if !count_only {
+                    let failed: Vec<&str> = checks.iter().map(|c| c.host.as_str()).collect();
+                    eprintln!("no valid signatures (failed: {})", failed.join(", "));
+                }
+                return Ok(false);
+            }
+        }
+        "hash-nar" => {
+            flags.finish()?;
+            // NARs can be huge, so stream them rather than using read_stdin
+            let (hash, size) = narinfo::hash_nar(std::io::stdin().lock(), globals.chunk_size)?;
+            println!("NarHash: {}", hash);
+            println!("NarSize: {}", size);
+        }
+        "full-verify" => {
+            let allow_unsigned = flags.switch("--allow-unsigned");
+            let trust_embedded = flags.switch("--trust-embedded-keys");
+            let nar = match flags.finish()?.as_slice() {
+                [nar] => nar.clone(),
+                _ => bail!("full-verify requires exactly one NAR file"),
+            };
+
+            let store_dir = narinfo::Store::new(&globals.store_dir()?)?;
+            let content = globals.read_stdin()?;
+            let trusted = trusted_keys(None)?;
+            let trusted = keys_for_narinfo(&trusted, &content, trust_embedded)?;
+
+            // report the NAR and the signatures separately, so that it's
+            // clear which of them is wrong
+            let mismatches =
+                narinfo::check_nar(&content, std::fs::File::open(&nar)?, globals.chunk_size)?;
+            if mismatches.is_empty() {
+                println!("{}: ok", nar);
+            }
+            for m in mismatches.iter() {
+                println!("{}: {}", nar, m);
+            }
+
+            let checks = narinfo::verify_narinfo(&store_dir, &trusted, &content, warnings)?;
+            for c in checks.iter() {
+                println!("{}: {}", c.host, c.status);
+            }
+            let verified = narinfo::verified(&checks, allow_unsigned)?;
+            if !verified {
+                eprintln!("no valid signatures");
+            }
+            return Ok(mismatches.is_empty() && verified);
+        }
+        "verify-batch" => {
+            return verify_batch_mode(flags, globals, warnings, &mut std::io::stdout().lock());
+        }
+        "normalize-hash" => {
+            let keep_sigs = flags.switch("--keep-sigs");
+            flags.finish()?;
+            let content = globals.read_stdin()?;
+            print!("{}", narinfo::normalize_hashes(&content, keep_sigs)?);
+        }
+        "rewrite-store-dir" => {
+            let from = flags.value("--from")?;
+            let to = flags.value("--to")?;
+            let sign = flags.switch("--sign");
+            let key_file = flags.value("--key-file")?;
+            flags.finish()?;
+            let (from, to) = match (from, to) {
+                (Some(from), Some(to)) => (narinfo::Store::new(&from)?, narinfo::Store::new(&to)?),
+                _ => bail!("rewrite-store-dir requires both --from and --to"),
+            };
+
+            let content = globals.read_stdin()?;
+            let mut info: narinfo::NarInfo = content.parse()?;
+            info.rewrite_store_dir(&from, &to)?;
+            let body = info.to_string();
+
+            // the old signatures are gone, so optionally make a new one
+            let keys = match key_file {
+                Some(path) => Some(narinfo::load_secret_key_file(Path::new(&path))?),
+                None if sign => Some(sk_to_keypair(&globals.signing_key()?)?),
+                None => None,
+            };
+            match keys {
+                Some(keys) => {
+                    let sig = narinfo::sign_narinfo(&to, &keys, &body)?;
+                    println!("{}", narinfo::append_signature(&body, &sig));
+                }
+                None => print!("{}", body),
+            }
+        }
+        "validate" => {
+            let self_reference = flags.switch("--reference-closure-check");
+            let ca_path = flags.switch("--verify-store-path-hash");
+            flags.finish()?;
+            let store_dir = narinfo::Store::new(&globals.store_dir()?)?;
+
+            let content = globals.read_stdin()?;
+            narinfo::validate_narinfo(&store_dir, &content, warnings)?;
+            if self_reference {
+                narinfo::check_self_reference(&store_dir, &content, warnings)?;
+            }
+            if ca_path && !narinfo::check_ca_store_path(&store_dir, &content)? {
+                warnings.warn("no CA field, so the StorePath can't be checked");
+            }
+        }
+        "diff" => {
+            let files = flags.finish()?;
+            if files.len() != 2 {
+                bail!("diff requires exactly two files");
+            }
+            let left = globals.read_file(&files[0])?;
+            let right = globals.read_file(&files[1])?;
+
+            let diffs = narinfo::diff_narinfos(&left, &right);
+            for d in diffs.iter() {
+                if let Some(v) = &d.left {
+                    println!("- {}: {}", d.field, v);
+                }
+                if let Some(v) = &d.right {
+                    println!("+ {}: {}", d.field, v);
+                }
+            }
+
+            // like diff(1), differences are a failure
+            return Ok(diffs.is_empty());
+        }
+        "scan" => {
+            let only_valid = flags.switch("--only-valid");
+            let input_dir = flags.value("--input-dir")?;
+            let recursive = flags.switch("--recursive");
+            let max_depth = flags.value("--max-depth")?.map(|n| n.parse()).transpose()?;
+            let jobs = parallel_jobs(&mut flags)?;
+            let mut files = flags.finish()?;
+            if let Some(dir) = input_dir {
+                let depth = if recursive { max_depth } else { Some(0) };
+                files.extend(find_narinfos(Path::new(&dir), depth)?);
+            }
+            let stores = narinfo::MultiStore::parse(&globals.store_dir()?)?;
+
+            let results = {
+                let globals = globals.clone();
+                map_files(&files, jobs, move |file| {
+                    let mut ws = narinfo::Warnings::new();
+                    (scan_file(&stores, file, &globals, &mut ws), ws)
+                })
+            };
+
+            let mut failed = 0;
+            for (file, (result, ws)) in files.iter().zip(results) {
+                for w in ws.messages() {
+                    warnings.warn(w.clone());
+                }
+                match result {
+                    // just the file names, so the output can go straight to
+                    // something like xargs
+                    Ok(()) if only_valid => println!("{}", file),
+                    Ok(()) => println!("{}: ok", file),
+                    Err(_) if only_valid => failed += 1,
+                    Err(e) => {
+                        println!("{}: error: {}", file, e);
+                        failed += 1;
+                    }
+                }
+            }
+
+            if only_valid {
+                if failed > 0 {
+                    eprintln!("skipped {} invalid narinfo(s)", failed);
+                }
+            } else if failed > 0 {
+                eprintln!("{} of {} narinfos failed to validate", failed, files.len());
+                return Ok(false);
+            }
+        }
+        "store-path-hash" => {
+            let typ = flags.value("--type")?;
+            let hash = flags.value("--hash")?;
+            let name = flags.value("--name")?;
+            flags.finish()?;
+
+            let (typ, hash, name) = match (typ, hash, name) {
+                (Some(typ), Some(hash), Some(name)) => (typ, hash, name),
+                _ => bail!("store-path-hash requires --type, --hash and --name"),
+            };
+            let store_dir = narinfo::Store::new(&globals.store_dir()?)?;
+            println!("{}", store_dir.store_path_hash(&typ, &hash, &name)?);
+        }
+        "make-cache-info" => {
+            let priority = match flags.value("--priority")? {
+                Some(p) => match p.parse::<u64>() {
+                    Ok(p) => Some(p),
+                    Err(_) => bail!("--priority must be a number: {}", p),
+                },
+                None => None,
+            };
+            let want_mass_query = flags.switch("--want-mass-query");
+            flags.finish()?;
+
+            print!(
+                "{}",
+                cache_info(&globals.store_dir()?, priority, want_mass_query)
+            );
+        }
+        "compare-keys" => {
+            let (a, b) = match flags.finish()?.as_slice() {
+                [a, b] => (a.clone(), b.clone()),
+                _ => bail!("compare-keys requires exactly two public keys"),
+            };
+            if narinfo::same_public_key(&a, &b)? {
+                println!("same key");
+            } else {
+                println!("different keys");
+                return Ok(false);
+            }
+        }
+        "gen-key" => {
+            let emit = flags.value("--emit-keys-file")?;
+            let force = flags.switch("--force");
+            let seed = flags.value("--seed")?;
+            let host = match flags.finish()?.as_slice() {
+                [host] => host.clone(),
+                _ => bail!("gen-key requires exactly one key name, e.g. cache.example.org-1"),
+            };
+
+            let keys = match seed {
+                Some(hex) => narinfo::keypair_from_seed(&host, &narinfo::parse_seed(&hex)?)?,
+                None => narinfo::Keys::generate(&host),
+            };
+            match emit {
+                Some(base) => narinfo::write_keys_file(Path::new(&base), &keys, force)?,
+                None => {
+                    println!("{}", keys.secret_key());
+                    println!("{}", keys.public_key());
+                }
+            }
+        }
+        // hidden, since it's only for sizing up a machine
+        "bench" => {
+            let count = match flags.value("--count")? {
+                Some(n) => match n.parse()? {
+                    0 => bail!("--count must be greater than zero"),
+                    n => n,
+                },
+                None => 1000,
+            };
+            flags.finish()?;
+
+            let (elapsed, rate) = bench(count)?;
+            println!(
+                "signed {} narinfos in {:.3}s ({:.0} signatures/sec)",
+                count,
+                elapsed.as_secs_f64(),
+                rate
+            );
         }
         "sk-to-pk" => {
-            let sk = env::var("NIX_SIGNING_KEY")?;
+            flags.finish()?;
+            let sk = globals.signing_key()?;
             let pk = sk_to_pk(&sk)?;
             println!("{}", pk);
         }
+        "key-digest" => {
+            let key_file = flags.value("--key-file")?;
+            flags.finish()?;
+
+            let keys = match key_file {
+                Some(path) => narinfo::load_secret_key_file(Path::new(&path))?,
+                None => sk_to_keypair(&globals.signing_key()?)?,
+            };
+            let digest = serde_json::json!({
+                "host": keys.host,
+                "publicKey": keys.public_key(),
+                "secretKeyFingerprint": keys.secret_key_digest(),
+            });
+            println!("{}", digest);
+        }
         _ => {
             eprintln!("Unknown mode: {}", mode);
-            std::process::exit(1);
+            return Ok(false);
         }
     }
 
-    Ok(())
+    Ok(true)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Options which apply to every mode.
+#[derive(Clone)]
+struct Globals {
+    /// The size of the buffer used to read input, via `--chunk-size`.
+    chunk_size: usize,
+    /// Whether to replace invalid UTF-8 in the input, via `--lossy`.
+    lossy: bool,
+    /// The most `References` a narinfo may have, via `--max-references`.
+    max_references: usize,
+    /// The longest a line of a narinfo may be, via `--max-line-length`.
+    max_line_length: Option<usize>,
+    /// The environment variable holding the signing key, via `--key-env`.
+    key_env: String,
+    /// The environment variable holding the store dir, via `--store-env`.
+    store_env: String,
+    /// The store dir given with `--store-dir`, which beats the environment.
+    store_dir: Option<String>,
+    /// Whether to reject input that isn't exactly `Key: value`, via
+    /// `--strict-colon`.
+    strict_colon: bool,
+    /// The fields every narinfo must have, via `--require-fields`.
+    required_fields: Vec<String>,
+}
+
+impl Globals {
+    fn signing_key(&self) -> Result<String> {
+        Ok(env::var(&self.key_env)?)
+    }
+
+    fn store_dir(&self) -> Result<String> {
+        if let Some(dir) = &self.store_dir {
+            return Ok(dir.clone());
+        }
+        match env::var(&self.store_env) {
+            Ok(dir) => Ok(dir),
+            Err(env::VarError::NotPresent) => bail!(
+                "{} is not set; set it to the store directory (e.g. /nix/store), or pass --store-dir",
+                self.store_env
+            ),
+            Err(e) => bail!("{}: {}", self.store_env, e),
+        }
+    }
+
+    fn read_stdin(&self) -> Result<String> {
+        let content = narinfo::read_chunked_limited(
+            std::io::stdin().lock(),
+            self.chunk_size,
+            self.lossy,
+            self.max_line_length,
+        )?;
+        self.check(&content)?;
+        Ok(content)
+    }
+
+    /// The checks done on every narinfo once it's been read in. The line
+    /// length is checked while reading instead, so that an enormous line is
+    /// turned away before it's all in memory.
+    fn check(&self, content: &str) -> Result<()> {
+        narinfo::check_max_references(content, self.max_references)?;
+        if self.strict_colon {
+            narinfo::check_strict_colons(content)?;
+        }
+        narinfo::check_required_fields(content, &self.required_fields)?;
+        Ok(())
+    }
+
+    /// Read the narinfo from a file, if one was given, or from stdin if not.
+    fn read_input(&self, files: &[String]) -> Result<String> {
+        match files {
+            [] => self.read_stdin(),
+            [file] => self.read_file(file),
+            _ => bail!("expected at most one file, but got {}", files.len()),
+        }
+    }
+
+    fn read_file(&self, path: &str) -> Result<String> {
+        let content = narinfo::read_chunked_limited(
+            std::fs::File::open(path)?,
+            self.chunk_size,
+            self.lossy,
+            self.max_line_length,
+        )?;
+        self.check(&content)?;
+        Ok(content)
+    }
+}
+
+/// Find the NAR URL of each of a narinfo's references, from their own
+/// narinfos in a local copy of the cache. References which aren't there are
+/// left out, and one that isn't a `<hash>-<name>` store path is an error,
+/// since its hash is used as a file name.
+fn ref_urls(cache_dir: &Path, content: &str) -> Result<HashMap<String, String>> {
+    let mut urls = HashMap::new();
+    let refs = narinfo::find_field(content, "References").unwrap_or("");
+    for r in refs.split_whitespace() {
+        // the hash has to be checked before it goes anywhere near the path,
+        // or a reference like ../../x-foo would escape the cache dir
+        let hash = match r.split_once('-') {
+            Some((hash, name))
+                if hash.len() == 32
+                    && narinfo_tools::base32::decode(hash).is_ok()
+                    && !name.is_empty()
+                    && !name.contains('/') =>
+            {
+                hash
+            }
+            _ => bail!(
+                "invalid reference, expected <32 character hash>-<name>: {}",
+                r
+            ),
+        };
+        let path = cache_dir.join(format!("{}.narinfo", hash));
+        let info = match std::fs::read_to_string(&path) {
+            Ok(info) => info,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => bail!("{}: {}", path.display(), e),
+        };
+        if let Some(url) = narinfo::find_field(&info, "URL") {
+            urls.insert(hash.to_string(), url.to_string());
+        }
+    }
+    Ok(urls)
+}
+
+/// Load the keys to verify narinfos with: those in `NIX_TRUSTED_PUBLIC_KEYS`,
+/// and any retired ones from a file.
+fn trusted_keys(retired: Option<String>) -> Result<narinfo::TrustStore> {
+    let mut trusted = match env::var("NIX_TRUSTED_PUBLIC_KEYS") {
+        Ok(pks) => narinfo::TrustStore::parse(&pks)?,
+        Err(_) => narinfo::TrustStore::new(),
+    };
+    if let Some(path) = retired {
+        for pk in std::fs::read_to_string(path)?.split_whitespace() {
+            trusted.add_retired(pk)?;
+        }
+    }
+    Ok(trusted)
+}
+
+/// The keys to verify one narinfo with. Its own `# trusted-keys:` lines only
+/// count with `--trust-embedded-keys`, since otherwise whoever wrote the
+/// narinfo could vouch for their own signature.
+fn keys_for_narinfo<'a>(
+    trusted: &'a narinfo::TrustStore,
+    content: &str,
+    trust_embedded: bool,
+) -> Result<Cow<'a, narinfo::TrustStore>> {
+    let trusted = if trust_embedded {
+        let mut trusted = trusted.clone();
+        trusted.add_embedded(content)?;
+        Cow::Owned(trusted)
+    } else {
+        Cow::Borrowed(trusted)
+    };
+    if trusted.is_empty() {
+        match trust_embedded {
+            true => bail!("no trusted keys in NIX_TRUSTED_PUBLIC_KEYS or a # trusted-keys: line"),
+            false => bail!("NIX_TRUSTED_PUBLIC_KEYS doesn't contain any keys"),
+        }
+    }
+    Ok(trusted)
+}
+
+/// Only trust the StorePath to tell us where the store is if the user
+/// explicitly asked for it with `--store-dir-from-path`; otherwise a narinfo
+/// could pick any store dir it likes.
+fn store_for(body: &str, infer_store_dir: bool, globals: &Globals) -> Result<narinfo::Store> {
+    if infer_store_dir {
+        match narinfo::find_field(body, "StorePath") {
+            Some(path) => narinfo::Store::from_store_path(path),
+            None => bail!("no StorePath found"),
+        }
+    } else {
+        narinfo::Store::new(&globals.store_dir()?)
+    }
+}
+
+/// Parse `--parallel N`, the number of threads a batch mode may use.
+fn parallel_jobs(flags: &mut Flags) -> Result<usize> {
+    match flags.value("--parallel")? {
+        Some(n) => match n.parse()? {
+            0 => bail!("--parallel must be greater than zero"),
+            n => Ok(n),
+        },
+        None => Ok(1),
+    }
+}
+
+/// Run `f` on every file, using up to `jobs` threads, and give back the
+/// results in the same order as the files, so that the output doesn't depend
+/// on how the work was scheduled. WASI can't spawn threads, so if that fails
+/// the work all just happens on the current thread instead.
+fn map_files<T, F>(files: &[String], jobs: usize, f: F) -> Vec<T>
+where
+    T: Send + 'static,
+    F: Fn(&str) -> T + Send + Sync + 'static,
+{
+    let files = Arc::new(files.to_vec());
+    let results = Arc::new(Mutex::new(
+        (0..files.len()).map(|_| None).collect::<Vec<Option<T>>>(),
+    ));
+    let work = {
+        let (files, results, next, f) = (
+            files.clone(),
+            results.clone(),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(f),
+        );
+        move || loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            if i >= files.len() {
+                break;
+            }
+            let result = f(&files[i]);
+            results.lock().unwrap()[i] = Some(result);
+        }
+    };
+
+    let mut threads = Vec::new();
+    for _ in 1..jobs.min(files.len()) {
+        match std::thread::Builder::new().spawn(work.clone()) {
+            Ok(t) => threads.push(t),
+            Err(_) => break,
+        }
+    }
+    work();
+    for t in threads {
+        if let Err(e) = t.join() {
+            std::panic::resume_unwind(e);
+        }
+    }
+
+    let results = std::mem::take(&mut *results.lock().unwrap());
+    results.into_iter().map(|r| r.unwrap()).collect()
+}
+
+/// Run the `verify-batch` mode, writing the result for each file and then a
+/// summary to `out`, and returning whether every file passed.
+fn verify_batch_mode(
+    mut flags: Flags,
+    globals: &Globals,
+    warnings: &mut narinfo::Warnings,
+    out: &mut impl Write,
+) -> Result<bool> {
+    let allow_unsigned = flags.switch("--allow-unsigned");
+    let trust_embedded = flags.switch("--trust-embedded-keys");
+    let jobs = parallel_jobs(&mut flags)?;
+    let files = flags.finish()?;
+
+    let store_dir = narinfo::Store::new(&globals.store_dir()?)?;
+    let trusted = trusted_keys(None)?;
+    // with embedded keys, each file may still bring its own
+    if !trust_embedded {
+        keys_for_narinfo(&trusted, "", false)?;
+    }
+
+    let (passed, failed) = verify_batch(
+        &files,
+        &store_dir,
+        &trusted,
+        allow_unsigned,
+        trust_embedded,
+        jobs,
+        globals,
+        warnings,
+        out,
+    )?;
+    writeln!(out, "{} passed, {} failed", passed, failed)?;
+    Ok(failed == 0)
+}
+
+/// Verify each narinfo file for the `verify-batch` mode, writing whether each
+/// one passed to `out`, and returning how many passed and failed.
+#[allow(clippy::too_many_arguments)]
+fn verify_batch(
+    files: &[String],
+    store: &narinfo::Store,
+    trusted: &narinfo::TrustStore,
+    allow_unsigned: bool,
+    trust_embedded: bool,
+    jobs: usize,
+    globals: &Globals,
+    warnings: &mut narinfo::Warnings,
+    out: &mut impl Write,
+) -> Result<(usize, usize)> {
+    let results = {
+        let (store, trusted, globals) = (store.clone(), trusted.clone(), globals.clone());
+        map_files(files, jobs, move |file| {
+            let mut ws = narinfo::Warnings::new();
+            let result = globals.read_file(file).and_then(|content| {
+                let trusted = keys_for_narinfo(&trusted, &content, trust_embedded)?;
+                let checks = narinfo::verify_narinfo(&store, &trusted, &content, &mut ws)?;
+                narinfo::verified(&checks, allow_unsigned)
+            });
+            (result, ws)
+        })
+    };
+
+    let (mut passed, mut failed) = (0, 0);
+    for (file, (result, ws)) in files.iter().zip(results) {
+        for w in ws.messages() {
+            warnings.warn(w.clone());
+        }
+        match result {
+            Ok(true) => {
+                writeln!(out, "{}: ok", file)?;
+                passed += 1;
+            }
+            Ok(false) => {
+                writeln!(out, "{}: failed: no valid signatures", file)?;
+                failed += 1;
+            }
+            Err(e) => {
+                writeln!(out, "{}: failed: {}", file, e)?;
+                failed += 1;
+            }
+        }
+    }
+    Ok((passed, failed))
+}
+
+/// Find every `*.narinfo` file in a directory for the `scan` mode, going at
+/// most `max_depth` subdirectories down, if given. Symlinks to directories
+/// aren't followed, so there's no way to loop forever.
+fn find_narinfos(dir: &Path, max_depth: Option<usize>) -> Result<Vec<String>> {
+    let mut found = Vec::new();
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.path());
+
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            match max_depth {
+                Some(0) => {}
+                depth => found.extend(find_narinfos(&path, depth.map(|d| d - 1))?),
+            }
+        } else if path.extension() == Some("narinfo".as_ref()) {
+            found.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(found)
+}
+
+/// Validate a single narinfo file for the `scan` mode, using whichever store
+/// its `StorePath` lives in.
+fn scan_file(
+    stores: &narinfo::MultiStore,
+    file: &str,
+    globals: &Globals,
+    warnings: &mut narinfo::Warnings,
+) -> Result<()> {
+    let content = globals.read_file(file)?;
+
+    let mut ws = narinfo::Warnings::new();
+    let result = stores.validate(&content, &mut ws);
+    for w in ws.messages() {
+        warnings.warn(format!("{}: {}", file, w));
+    }
+    result
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// The command line flags given to the tool. Global flags are removed first,
+/// then the mode name, and then each mode pulls out the flags it understands
+/// before calling [`Flags::finish`] to reject anything left over.
+struct Flags {
+    args: Vec<String>,
+    /// The flags listed in [`MODES`] for the mode being run, once it's known.
+    listed: Option<&'static [Flag]>,
+}
+
+impl Flags {
+    fn new(args: &[String]) -> Flags {
+        Flags {
+            args: args.to_vec(),
+            listed: None,
+        }
+    }
+
+    /// Remove the name of the mode to run, which must come first after any
+    /// global flags have been removed.
+    fn mode(&mut self) -> Option<String> {
+        match self.args.first() {
+            Some(a) if !a.starts_with("--") => {
+                let mode = self.args.remove(0);
+                self.listed = MODES
+                    .iter()
+                    .find(|(name, _, _)| *name == mode)
+                    .map(|(_, _, listed)| *listed);
+                Some(mode)
+            }
+            _ => None,
+        }
+    }
+
+    /// `<mode> --help` is made from [`MODES`], so every flag a mode looks for
+    /// has to be listed there too, or it would be missing from the help.
+    fn check_listed(&self, name: &str) {
+        if let Some(listed) = self.listed {
+            debug_assert!(
+                listed
+                    .iter()
+                    .any(|(flag, _)| flag.split(' ').next() == Some(name)),
+                "{} isn't listed for its mode in MODES",
+                name
+            );
+        }
+    }
+
+    /// Remove a boolean `--flag`, returning whether it was given.
+    fn switch(&mut self, name: &str) -> bool {
+        self.check_listed(name);
+        let len = self.args.len();
+        self.args.retain(|a| a != name);
+        self.args.len() != len
+    }
+
+    /// Remove an `--option <value>`, returning its value if it was given.
+    fn value(&mut self, name: &str) -> Result<Option<String>> {
+        self.check_listed(name);
+        let i = match self.args.iter().position(|a| a == name) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        if i + 1 >= self.args.len() {
+            bail!("{} requires a value", name);
+        }
+        let v = self.args.remove(i + 1);
+        self.args.remove(i);
+        Ok(Some(v))
+    }
+
+    /// Remove every occurrence of an `--option <value>` which may be given
+    /// multiple times, returning all the values in order.
+    fn values(&mut self, name: &str) -> Result<Vec<String>> {
+        let mut vs = Vec::new();
+        while let Some(v) = self.value(name)? {
+            vs.push(v);
+        }
+        Ok(vs)
+    }
+
+    /// Return the remaining positional arguments, failing if there are any
+    /// flags that weren't consumed by the mode.
+    fn finish(self) -> Result<Vec<String>> {
+        if let Some(unknown) = self.args.iter().find(|a| a.starts_with("--")) {
+            bail!("unknown flag: {}", unknown);
+        }
+        Ok(self.args)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        bench, find_narinfos, help_line, list_modes, mode_usage, narinfo, usage, verify_batch,
+        verify_batch_mode, Flags, Globals, MODES,
+    };
+
+    const YOSYS: &str = "StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
+NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
+NarSize: 17680416
+References: dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15";
+
+    fn test_globals(store_env: &str) -> Globals {
+        Globals {
+            chunk_size: narinfo::DEFAULT_CHUNK_SIZE,
+            lossy: false,
+            max_references: narinfo::DEFAULT_MAX_REFERENCES,
+            max_line_length: None,
+            key_env: "NARINFO_TOOLS_TEST_KEY".into(),
+            store_env: store_env.into(),
+            store_dir: None,
+            strict_colon: false,
+            required_fields: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_usage() {
+        let help = usage();
+        for (name, description, _) in MODES {
+            assert!(help.contains(&help_line(name, description)));
+        }
+        assert!(help.contains("--store-dir <dir>"));
+
+        let sign = mode_usage("sign").unwrap();
+        assert!(sign.starts_with("Sign a narinfo with NIX_SIGNING_KEY\n"));
+        assert!(sign.contains("  --keep-sigs "));
+        assert!(sign.contains("  --key-env <var> "));
+        assert!(!sign.contains("--version"));
+        // verify doesn't read the signing key
+        let verify = mode_usage("verify").unwrap();
+        assert!(verify.contains("  --accept-retired <file> "));
+        assert!(verify.contains("  --store-dir <dir> "));
+        assert!(!verify.contains("--key-env"));
+        assert!(!mode_usage("diff").unwrap().contains("Mode flags:"));
+        assert!(mode_usage("bogus").is_none());
+    }
+
+    #[test]
+    fn test_list_modes() {
+        let listing = list_modes(false);
+        let names: Vec<&str> = listing.lines().collect();
+        for mode in ["json", "sign", "verify", "sk-to-pk"] {
+            assert!(names.contains(&mode), "{} should be listed", mode);
+        }
+        assert_eq!(names.len(), MODES.len());
+
+        let json: serde_json::Value = serde_json::from_str(&list_modes(true)).unwrap();
+        assert_eq!(json[0]["name"], "json");
+        assert_eq!(json[0]["description"], "Convert a narinfo to JSON");
+    }
+
+    #[test]
+    fn test_bench() {
+        let (_, rate) = bench(5).unwrap();
+        assert!(rate > 0.0);
+        // it's hidden
+        assert!(!MODES.iter().any(|(name, _, _)| *name == "bench"));
+    }
+
+    #[test]
+    fn test_custom_env_names() {
+        let globals = test_globals("NARINFO_TOOLS_TEST_STORE");
+        std::env::set_var(
+            "NARINFO_TOOLS_TEST_KEY",
+            "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==",
+        );
+        std::env::set_var("NARINFO_TOOLS_TEST_STORE", "/nix/store");
+
+        let keys = narinfo::sk_to_keypair(&globals.signing_key().unwrap()).unwrap();
+        let store = narinfo::Store::new(&globals.store_dir().unwrap()).unwrap();
+        let sig = narinfo::sign_narinfo(&store, &keys, YOSYS).unwrap();
+        assert!(sig.starts_with("t:"));
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let keys = narinfo::Keys::generate("t");
+        let store = narinfo::Store::new("/nix/store").unwrap();
+        let sig = narinfo::sign_narinfo(&store, &keys, YOSYS).unwrap();
+
+        let dir = std::env::temp_dir().join(format!(
+            "narinfo-tools-test-verify-batch-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, content: String| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let good = write("good.narinfo", format!("{}\nSig: {}\n", YOSYS, sig));
+        let bad = write(
+            "bad.narinfo",
+            format!("{}\nSig: {}\n", YOSYS.replace("17680416", "17680417"), sig),
+        );
+        let unsigned = write("unsigned.narinfo", YOSYS.to_string());
+
+        let globals = test_globals("NARINFO_TOOLS_TEST_BATCH_STORE");
+        let trusted = narinfo::TrustStore::parse(&keys.public_key()).unwrap();
+        let files = vec![good.clone(), bad, unsigned];
+        let mut warnings = narinfo::Warnings::new();
+        let mut out = Vec::new();
+        let summary = verify_batch(
+            &files,
+            &store,
+            &trusted,
+            false,
+            false,
+            1,
+            &globals,
+            &mut warnings,
+            &mut out,
+        );
+        assert_eq!(summary.unwrap(), (1, 2));
+        let results = format!(
+            "{}: ok\n\
+             {}: failed: no valid signatures\n\
+             {}: failed: no signatures present\n",
+            files[0], files[1], files[2]
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), results);
+
+        // the whole mode adds a summary after the results
+        std::env::set_var("NARINFO_TOOLS_TEST_BATCH_STORE", "/nix/store");
+        std::env::set_var("NIX_TRUSTED_PUBLIC_KEYS", keys.public_key());
+        let run_batch = |files: &[String]| {
+            let mut out = Vec::new();
+            let passed = verify_batch_mode(
+                Flags::new(files),
+                &globals,
+                &mut narinfo::Warnings::new(),
+                &mut out,
+            )
+            .unwrap();
+            (passed, String::from_utf8(out).unwrap())
+        };
+        assert_eq!(
+            run_batch(&files),
+            (false, format!("{}1 passed, 2 failed\n", results))
+        );
+        assert_eq!(
+            run_batch(std::slice::from_ref(&good)),
+            (true, format!("{}: ok\n1 passed, 0 failed\n", good))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_batch_parallel() {
+        let keys = narinfo::Keys::generate("t");
+        let store = narinfo::Store::new("/nix/store").unwrap();
+        let sig = narinfo::sign_narinfo(&store, &keys, YOSYS).unwrap();
+
+        let dir = std::env::temp_dir().join(format!(
+            "narinfo-tools-test-verify-parallel-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files: Vec<String> = (0..40)
+            .map(|i| {
+                let path = dir.join(format!("{}.narinfo", i));
+                let size = if i % 3 == 0 { "17680417" } else { "17680416" };
+                let body = format!("{}\nSig: {}\n", YOSYS.replace("17680416", size), sig);
+                std::fs::write(&path, body).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect();
+
+        let globals = test_globals("NARINFO_TOOLS_TEST_PARALLEL_STORE");
+        let trusted = narinfo::TrustStore::parse(&keys.public_key()).unwrap();
+        let verify = |jobs| {
+            let mut out = Vec::new();
+            let summary = verify_batch(
+                &files,
+                &store,
+                &trusted,
+                false,
+                false,
+                jobs,
+                &globals,
+                &mut narinfo::Warnings::new(),
+                &mut out,
+            )
+            .unwrap();
+            (summary, String::from_utf8(out).unwrap())
+        };
+        let (summary, out) = verify(1);
+        assert_eq!(summary, (26, 14));
+        assert_eq!(verify(4), (summary, out));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_narinfos() {
+        let dir = std::env::temp_dir().join(format!(
+            "narinfo-tools-test-input-dir-{}",
+            std::process::id()
+        ));
+        let nested = dir.join("ab").join("cd");
+        std::fs::create_dir_all(&nested).unwrap();
+        let top = dir.join("top.narinfo");
+        let deep = nested.join("deep.narinfo");
+        std::fs::write(&top, YOSYS).unwrap();
+        std::fs::write(&deep, YOSYS).unwrap();
+        std::fs::write(dir.join("nix-cache-info"), "StoreDir: /nix/store\n").unwrap();
+
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+        assert_eq!(find_narinfos(&dir, Some(0)).unwrap(), vec![path(&top)]);
+        assert_eq!(find_narinfos(&dir, Some(1)).unwrap(), vec![path(&top)]);
+        assert_eq!(
+            find_narinfos(&dir, None).unwrap(),
+            vec![path(&deep), path(&top)]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_dir_unset() {
+        let mut globals = test_globals("NARINFO_TOOLS_TEST_UNSET_STORE");
+        let err = globals.store_dir().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "NARINFO_TOOLS_TEST_UNSET_STORE is not set; set it to the store directory (e.g. /nix/store), or pass --store-dir"
+        );
+
+        globals.store_dir = Some("/nix/store".into());
+        assert_eq!(globals.store_dir().unwrap(), "/nix/store");
+    }
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
@@ -5,61 +5,421 @@
 
 // ---------------------------------------------------------------------------------------------------------------------
 
-use std::collections::HashMap;
-use std::fmt::Write;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
-use anyhow::{bail, Result};
-use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand::rngs::OsRng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::base32;
+use crate::error::ParseError;
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// A collector for the non-fatal problems found while processing narinfos, so
+/// that they can all be reported together at the end, and optionally be
+/// treated as errors.
+#[derive(Debug, Default)]
+pub struct Warnings {
+    messages: Vec<String>,
+}
+
+impl Warnings {
+    pub fn new() -> Warnings {
+        Warnings::default()
+    }
+
+    pub fn warn(&mut self, message: impl Into<String>) {
+        self.messages.push(message.into());
+    }
+
+    pub fn messages(&self) -> &[String] {
+        &self.messages
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Print every warning to stderr, and then fail if `fail_on_warning` is
+    /// set and there were any at all.
+    pub fn finish(&self, fail_on_warning: bool) -> Result<()> {
+        for m in self.messages.iter() {
+            eprintln!("warning: {}", m);
+        }
+        if fail_on_warning && !self.is_empty() {
+            bail!(
+                "{} warning(s) emitted with --fail-on-warning",
+                self.messages.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// The default size of the buffer used by [`read_chunked`]: 64 KiB.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Read everything from a reader into a string, `chunk_size` bytes at a time.
+/// This allows tuning how large each read is, which can matter for
+/// throughput depending on whatever storage (or WASI host) is on the other
+/// end.
+///
+/// Invalid UTF-8 is an error, unless `lossy` is set, in which case it's
+/// replaced with `U+FFFD`; narinfos are nearly all ASCII, so one corrupt byte
+/// in a field shouldn't stop the rest of the file from being used.
+pub fn read_chunked<R: std::io::Read>(r: R, chunk_size: usize, lossy: bool) -> Result<String> {
+    read_chunked_limited(r, chunk_size, lossy, None)
+}
+
+/// Like [`read_chunked`], but fail as soon as any line gets longer than
+/// `max_line_length` bytes, if given, rather than reading the rest of it in
+/// first; that way a single enormous line never has to fit in memory.
+pub fn read_chunked_limited<R: std::io::Read>(
+    mut r: R,
+    chunk_size: usize,
+    lossy: bool,
+    max_line_length: Option<usize>,
+) -> Result<String> {
+    let mut buf = vec![0; chunk_size];
+    let mut content = Vec::new();
+    // the line being read, and how long it is so far; a CR is only counted
+    // once it's clear it isn't the end of a CRLF, like str::lines does
+    let (mut line, mut len, mut cr) = (1, 0, false);
+    loop {
+        match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                if let Some(max) = max_line_length {
+                    for &b in &buf[..n] {
+                        match b {
+                            b'\n' => (line, len, cr) = (line + 1, 0, false),
+                            b'\r' => (len, cr) = (len + cr as usize, true),
+                            _ => (len, cr) = (len + 1 + cr as usize, false),
+                        }
+                        if len > max {
+                            return Err(line_too_long(line, max));
+                        }
+                    }
+                }
+                content.extend_from_slice(&buf[..n]);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if lossy {
+        Ok(String::from_utf8_lossy(&content).into_owned())
+    } else {
+        Ok(String::from_utf8(content)?)
+    }
+}
 
 // ---------------------------------------------------------------------------------------------------------------------
 
 /// Parse an HTTP body containing a Nix `narinfo` file and produce a JSON
 /// response. This is an extension to the primary Nix infrastructure, which
 /// allows a bit easier querying.
+///
+/// This is exactly what the `json` mode prints, with the fields in the same
+/// order as the narinfo. Any warnings, e.g. about a malformed `Sig`, are
+/// dropped; use [`narinfo_to_json_with`] to collect them, or for more options.
 pub fn narinfo_to_json(body: String, out: &mut String) {
-    let mut prefix = "{";
-    let mut sigs = HashMap::new();
+    // none of the checks which can fail are on by default
+    let _ = narinfo_to_json_with(body, out, &JsonOptions::default(), &mut Warnings::new());
+}
+
+/// Options for tweaking the output of [`narinfo_to_json_with`].
+#[derive(Debug, Default)]
+pub struct JsonOptions<'a> {
+    /// Emit `References` and `Deriver` as absolute paths inside of this
+    /// store, rather than relative to it like the narinfo itself does.
+    pub abs_refs: Option<&'a Store>,
+    /// Emit each of the `References` as an object with the `path`, and its
+    /// `hash` and `name` parts split out, rather than as a plain string.
+    pub refs_detailed: bool,
+    /// Emit each of the `References` as an object with its `hash` and `name`,
+    /// and the `url` of its NAR, found by looking up its hash part in this
+    /// map. A reference that isn't in the map gets no `url`.
+    pub ref_urls: Option<&'a HashMap<String, String>>,
+    /// Add a derived `ContentAddressed` boolean, which is true when there's
+    /// a `CA` field, so consumers can filter on it without parsing `CA`.
+    pub content_addressed: bool,
+    /// Split the `Sig` object into `valid` and `invalid` objects, depending
+    /// on whether each signature verifies against these trusted keys, with
+    /// the fingerprint computed in this store.
+    pub group_sigs: Option<(&'a Store, &'a TrustStore)>,
+    /// Map every field to its exact text, without any of the special cases,
+    /// so that the JSON is a faithful copy of the narinfo. A field which
+    /// occurs more than once (i.e. `Sig`) becomes an array of its values.
+    pub no_special_cases: bool,
+    /// Accept a `Sig` without a `host:` prefix, as some non-Nix tools write
+    /// them, and put it under the [`BARE_SIG_HOST`] key.
+    pub lenient: bool,
+    /// Add a `Verification` object, with the `status` of the narinfo's
+    /// signatures against these trusted keys, and the host of the first valid
+    /// one it was signed `by`.
+    pub verification: Option<(&'a Store, &'a TrustStore)>,
+    /// Leave out any field with an empty value, e.g. a stray `Compression:`,
+    /// rather than emitting it as `""` (or `[]`, for `References`).
+    pub omit_empty: bool,
+    /// Fail on a field other than `Sig` which occurs more than once, rather
+    /// than letting the last one win, since it means the narinfo is corrupt.
+    pub strict: bool,
+}
+
+/// The host that a bare signature (i.e. one without a `host:` prefix) is
+/// given in the JSON, with [`JsonOptions::lenient`].
+pub const BARE_SIG_HOST: &str = "unknown";
+
+/// What Nix writes as the `Deriver` of a path that doesn't have one. It's a
+/// `null` in the JSON.
+pub const UNKNOWN_DERIVER: &str = "unknown-deriver";
+
+/// Like [`narinfo_to_json`], but with extra options, and collecting any
+/// warnings. Some of the options can reject a narinfo, e.g.
+/// [`strict`](JsonOptions::strict) one with a duplicated field, or
+/// [`group_sigs`](JsonOptions::group_sigs) one that can't be fingerprinted;
+/// then nothing is added to `out`.
+pub fn narinfo_to_json_with(
+    body: String,
+    out: &mut String,
+    opts: &JsonOptions,
+    warnings: &mut Warnings,
+) -> Result<()> {
+    // the JSON is a little bigger than the narinfo, so reserve up front
+    // rather than growing the buffer over and over for a huge References
+    let mut buf = Vec::with_capacity(body.len() + body.len() / 2);
+    narinfo_to_json_writer(&body, &mut buf, opts, warnings)?;
+    out.push_str(std::str::from_utf8(&buf)?);
+    Ok(())
+}
+
+/// Like [`narinfo_to_json_with`], but write the JSON straight to `out`,
+/// rather than into an intermediate string first.
+///
+/// The object is always followed by exactly one newline, so that several of
+/// them can be written one after the other as NDJSON. If the body doesn't
+/// contain any fields at all then nothing is written, not even the newline.
+pub fn narinfo_to_json_writer<W: std::io::Write>(
+    body: &str,
+    out: &mut W,
+    opts: &JsonOptions,
+    warnings: &mut Warnings,
+) -> Result<()> {
+    let fields = narinfo_to_json_map(body, opts, warnings)?;
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    let mut ser = serde_json::Serializer::with_formatter(&mut *out, SpacedFormatter::default());
+    serde_json::Value::Object(fields).serialize(&mut ser)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// A buffer for converting a whole batch of narinfos to JSON one at a time,
+/// which is cleared and reused for each record rather than allocating a new
+/// one every time; that adds up under `wee_alloc`, which is slow to allocate
+/// and never gives memory back to the system.
+#[derive(Debug, Default)]
+pub struct JsonBuffer {
+    buf: Vec<u8>,
+}
+
+impl JsonBuffer {
+    pub fn new() -> JsonBuffer {
+        JsonBuffer::default()
+    }
+
+    /// Convert a narinfo like [`narinfo_to_json_writer`], returning the JSON,
+    /// which is only valid until the next call.
+    pub fn convert(
+        &mut self,
+        body: &str,
+        opts: &JsonOptions,
+        warnings: &mut Warnings,
+    ) -> Result<&str> {
+        self.buf.clear();
+        narinfo_to_json_writer(body, &mut self.buf, opts, warnings)?;
+        Ok(std::str::from_utf8(&self.buf)?)
+    }
+}
+
+/// Convert a stream of narinfos, separated by blank lines, to NDJSON: one
+/// JSON object per line of `out`. Only one narinfo is held in memory at a
+/// time, so the input can be as long as it likes. Returns the number of
+/// narinfos converted.
+///
+/// Each narinfo is passed to `check` before it's converted, and the first
+/// error from it stops the whole batch. A line longer than `max_line_length`
+/// is an error as soon as that many bytes of it have been read.
+pub fn narinfos_to_ndjson<R: std::io::BufRead, W: std::io::Write>(
+    mut input: R,
+    out: &mut W,
+    opts: &JsonOptions,
+    lossy: bool,
+    max_line_length: Option<usize>,
+    mut check: impl FnMut(&str) -> Result<()>,
+    warnings: &mut Warnings,
+) -> Result<usize> {
+    use std::io::{BufRead, Read};
+
+    let mut json = JsonBuffer::new();
+    let mut record = String::new();
+    let mut line = Vec::new();
+    let mut count = 0;
+    for n in 1.. {
+        line.clear();
+        let eof = match max_line_length {
+            // room for the CRLF too, which doesn't count towards the length
+            Some(max) => {
+                let limit = max as u64 + 2;
+                let eof = input.by_ref().take(limit).read_until(b'\n', &mut line)? == 0;
+                let text = line.strip_suffix(b"\n").unwrap_or(&line);
+                if text.strip_suffix(b"\r").unwrap_or(text).len() > max {
+                    return Err(line_too_long(n, max));
+                }
+                eof
+            }
+            None => input.read_until(b'\n', &mut line)? == 0,
+        };
+        let text = if lossy {
+            String::from_utf8_lossy(&line)
+        } else {
+            std::str::from_utf8(&line)?.into()
+        };
+
+        if eof || text.trim().is_empty() {
+            if !record.trim().is_empty() {
+                check(&record)?;
+                out.write_all(json.convert(&record, opts, warnings)?.as_bytes())?;
+                count += 1;
+            }
+            record.clear();
+            if eof {
+                break;
+            }
+        } else {
+            record.push_str(&text);
+        }
+    }
+    Ok(count)
+}
+
+/// Build the JSON object for a narinfo, with the fields in the same order as
+/// the narinfo itself, followed by anything derived from them.
+fn narinfo_to_json_map(
+    body: &str,
+    opts: &JsonOptions,
+    warnings: &mut Warnings,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    use serde_json::{json, Map, Value};
+
+    if opts.strict {
+        check_duplicate_fields(body)?;
+    }
+    if opts.no_special_cases {
+        return Ok(raw_json_map(body, opts.omit_empty, warnings));
+    }
+
+    // references and derivers are relative in the narinfo, but the user may
+    // want them absolute. be careful not to prefix a deriver that something
+    // else already made absolute, though.
+    let path = |p: &str| match opts.abs_refs {
+        Some(store) if !p.starts_with('/') => format!("{}/{}", store.store_path, p),
+        _ => p.to_string(),
+    };
+    let number = |k: &str, v: &str, warnings: &mut Warnings| match v.parse::<u64>() {
+        Ok(n) => Value::from(n),
+        Err(_) => {
+            warnings.warn(format!("{} is not a number: {}", k, v));
+            Value::from(v)
+        }
+    };
+
+    let mut fields = Map::new();
+    let mut sigs = Map::new();
     for x in body.lines() {
         let x = x.trim();
-        if x == "" {
+        if x.is_empty() {
+            continue;
+        }
+        let (k, v) = match split_once(x) {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => {
+                warnings.warn(format!("ignoring line without a field name: {}", x));
+                continue;
+            }
+        };
+        if opts.omit_empty && v.is_empty() {
             continue;
         }
-        let (k, v) = split_once(x);
-        let (k, v) = (k.trim(), v.trim());
 
         // handle some special cases and emit them in a more appropriate JSON
         // equivalents.
-        match k {
+        let value = match k {
             // normal narinfo files
-            "FileSize" | "NarSize" => {
-                write!(out, "{} \"{}\": {}", prefix, k, v).unwrap();
-            }
-            "FileHash" | "NarHash" => {
-                let (typ, hash) = split_once(v);
-
-                write!(
-                    out,
-                    "{} \"{}\": {{ \"type\": \"{}\", \"hash\": \"{}\" }}",
-                    prefix, k, typ, hash
-                )
-                .unwrap();
-            }
+            "FileSize" | "NarSize" => number(k, v, warnings),
+            k if is_hash_field(k) => match split_once(v) {
+                Some((typ, hash)) => json!({ "type": typ, "hash": hash }),
+                // an SRI hash's base64 can't be told apart from base32 once
+                // it's split up, so it gets the base32 form instead
+                None => match split_sri(v) {
+                    Some(Ok((typ, hash))) => json!({ "type": typ, "hash": base32::encode(&hash) }),
+                    Some(Err(e)) => {
+                        warnings.warn(e.to_string());
+                        Value::from(v)
+                    }
+                    None => {
+                        warnings.warn(format!("{} has no hash type: {}", k, v));
+                        Value::from(v)
+                    }
+                },
+            },
 
-            "References" => {
-                let mut prefix2 = "[";
-                let mut out2 = String::new();
+            "References" => v
+                .split_whitespace()
+                .map(|y| {
+                    if !opts.refs_detailed && opts.ref_urls.is_none() {
+                        return Value::from(path(y));
+                    }
+                    let (hash, name) = y.split_once('-').unwrap_or((y, ""));
+                    let mut r = Map::new();
+                    if opts.refs_detailed {
+                        r.insert("path".into(), path(y).into());
+                    }
+                    r.insert("hash".into(), hash.into());
+                    r.insert("name".into(), name.into());
+                    if let Some(url) = opts.ref_urls.and_then(|urls| urls.get(hash)) {
+                        r.insert("url".into(), url.as_str().into());
+                    }
+                    Value::Object(r)
+                })
+                .collect(),
 
-                write!(out, "{} ", prefix).unwrap();
-                for y in v.split_whitespace() {
-                    write!(out2, "{} \"{}\"", prefix2, y).unwrap();
-                    prefix2 = ",";
-                }
+            "Deriver" if v == UNKNOWN_DERIVER => Value::Null,
+            "Deriver" => Value::from(path(v)),
 
-                if prefix2 != "[" {
-                    write!(out2, " ]").unwrap();
+            // content-addressed paths, e.g. 'fixed:r:sha256:<hash>'. the
+            // method can itself contain colons, so the hash is whatever's
+            // after the last one.
+            "CA" => match v.rsplit_once(':') {
+                Some((method, hash)) => json!({ "method": method, "hash": hash }),
+                None => {
+                    warnings.warn(format!("CA has no method: {}", v));
+                    Value::from(v)
                 }
-                write!(out, "\"{}\": {}", k, out2).unwrap();
-            }
+            },
+
+            // some caches list mirrors here too, but most consumers expect a
+            // plain string, so only use an array when there's more than one
+            "URL" if v.split_whitespace().nth(1).is_some() => v.split_whitespace().collect(),
 
             "Sig" => {
                 // 'Sig' is the only key in a narinfo file that can occur
@@ -73,276 +433,4782 @@ pub fn narinfo_to_json(body: String, out: &mut String) {
                 // return multiple signatures. Therefore, to handle this, we
                 // have to iterate and collect all the signatures first, then
                 // parse them.
-                let (host, sig) = split_once(v);
-                sigs.insert(host, sig);
+                match parse_sig(v) {
+                    Ok((host, sig)) => {
+                        sigs.insert(host.to_string(), tidy_base64(sig).into());
+                    }
+                    Err(_) if opts.lenient && !v.is_empty() && !v.contains(':') => {
+                        sigs.insert(BARE_SIG_HOST.to_string(), tidy_base64(v).into());
+                    }
+                    Err(e) => warnings.warn(format!("ignoring malformed Sig: {}", e)),
+                }
+                continue;
             }
 
             // nix-cache-info
-            "WantMassQuery" => {
-                write!(
-                    out,
-                    "{} \"{}\": {}",
-                    prefix,
-                    k,
-                    if v == "1" { "true" } else { "false" }
-                )
-                .unwrap();
-            }
-            "Priority" => {
-                write!(out, "{} \"{}\": {}", prefix, k, v).unwrap();
-            }
+            "WantMassQuery" => Value::from(v == "1"),
+            "Priority" => number(k, v, warnings),
 
-            _ => {
-                write!(out, "{} \"{}\": \"{}\"", prefix, k, v).unwrap();
-            }
-        }
+            _ => Value::from(v),
+        };
+        fields.insert(k.to_string(), value);
+    }
 
-        prefix = ",";
+    if fields.is_empty() {
+        // there weren't any rows at all
+        return Ok(fields);
     }
 
-    if prefix != "{" {
-        // there must have been at least one row
+    if let Some((store, trusted)) = opts.group_sigs {
+        let fp = fingerprint_narinfo(store, body)?;
+        let (valid, invalid): (Map<_, _>, Map<_, _>) = sigs.into_iter().partition(|(host, sig)| {
+            let sig = sig.as_str().unwrap_or("");
+            check_sig(trusted, &fp, host, sig).status.is_valid()
+        });
+        fields.insert("Sig".into(), json!({ "valid": valid, "invalid": invalid }));
+    } else if !sigs.is_empty() {
+        fields.insert("Sig".into(), sigs.into());
+    }
 
-        if sigs.len() > 0 {
-            write!(out, ", \"Sig\": ").unwrap();
+    if let Some((store, trusted)) = opts.verification {
+        // any malformed signatures were already warned about above
+        let checks = verify_narinfo(store, trusted, body, &mut Warnings::new())?;
+        let by = checks.iter().find(|c| c.status.is_valid()).map(|c| &c.host);
+        let status = match by {
+            Some(_) => "valid",
+            None if checks.is_empty() => "unsigned",
+            None => "invalid",
+        };
+        fields.insert("Verification".into(), json!({ "status": status, "by": by }));
+    }
 
-            let mut prefix3 = "{";
-            for (host, sig) in sigs.iter() {
-                write!(out, "{} \"{}\": \"{}\"", prefix3, host, sig).unwrap();
-                prefix3 = ",";
-            }
+    if opts.content_addressed {
+        let ca = find_field(body, "CA").is_some();
+        fields.insert("ContentAddressed".into(), ca.into());
+    }
 
-            write!(out, " }}").unwrap();
-        }
+    Ok(fields)
+}
+
+/// Whether a field holds an `algo:hash`, i.e. it's `FileHash` or `NarHash`,
+/// or an extension of them with the hash in another algorithm, such as
+/// `NarHashSha512`. Only `NarHash` itself is ever signed.
+fn is_hash_field(k: &str) -> bool {
+    k.starts_with("FileHash") || k.starts_with("NarHash")
+}
 
-        write!(out, " }}\n").unwrap();
+/// Build the JSON object for [`JsonOptions::no_special_cases`].
+fn raw_json_map(
+    body: &str,
+    omit_empty: bool,
+    warnings: &mut Warnings,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut fields: Vec<(&str, Vec<&str>)> = Vec::new();
+    for x in body.lines() {
+        let x = x.trim();
+        if x.is_empty() {
+            continue;
+        }
+        let (k, v) = match split_once(x) {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => {
+                warnings.warn(format!("ignoring line without a field name: {}", x));
+                continue;
+            }
+        };
+        if omit_empty && v.is_empty() {
+            continue;
+        }
+        match fields.iter_mut().find(|(f, _)| *f == k) {
+            Some((_, vs)) => vs.push(v),
+            None => fields.push((k, vec![v])),
+        }
     }
+
+    fields
+        .into_iter()
+        .map(|(k, vs)| {
+            let v = match vs.as_slice() {
+                [v] => serde_json::Value::from(*v),
+                vs => vs.iter().copied().collect(),
+            };
+            (k.to_string(), v)
+        })
+        .collect()
 }
 
-/// Split a string containing *at least one* colon `:` character into two
-/// strings at the first encounter.
-///
-/// Any remaining encounters are ignored and remain part of the second string.
-/// The colon character is removed.
-fn split_once(in_string: &str) -> (&str, &str) {
-    let mut splitter = in_string.splitn(2, ':');
-    let first = splitter.next().unwrap();
-    let second = splitter.next().unwrap();
-    (first, second)
+/// A JSON formatter which puts spaces inside of brackets and after commas,
+/// e.g. `{ "a": [ 1, 2 ] }`, which is how `narinfo_to_json` has always
+/// written things. Empty objects and arrays are just `{}` and `[]`.
+#[derive(Debug, Default)]
+struct SpacedFormatter {
+    /// Whether each of the objects or arrays being written has any values yet.
+    has_value: Vec<bool>,
 }
 
-// ---------------------------------------------------------------------------------------------------------------------
+impl SpacedFormatter {
+    fn begin<W: ?Sized + std::io::Write>(&mut self, w: &mut W, open: &[u8]) -> std::io::Result<()> {
+        self.has_value.push(false);
+        w.write_all(open)
+    }
 
-pub struct Keys<'a> {
-    pub host: &'a str,
-    pub keys: Keypair,
+    fn end<W: ?Sized + std::io::Write>(&mut self, w: &mut W, close: &[u8]) -> std::io::Result<()> {
+        if self.has_value.pop().unwrap_or(false) {
+            w.write_all(b" ")?;
+        }
+        w.write_all(close)
+    }
+
+    fn value<W: ?Sized + std::io::Write>(&mut self, w: &mut W, first: bool) -> std::io::Result<()> {
+        if let Some(x) = self.has_value.last_mut() {
+            *x = true;
+        }
+        w.write_all(if first { b" " } else { b", " })
+    }
 }
 
-pub fn sk_to_keypair(sk: &str) -> Result<Keys> {
-    let pieces: Vec<&str> = sk.split(":").collect();
-    if pieces.len() != 2 {
-        bail!("invalid sk: expected to contain hostname");
+impl serde_json::ser::Formatter for SpacedFormatter {
+    fn begin_array<W: ?Sized + std::io::Write>(&mut self, w: &mut W) -> std::io::Result<()> {
+        self.begin(w, b"[")
     }
 
-    let host = pieces[0];
-    let dat = pieces[1];
+    fn end_array<W: ?Sized + std::io::Write>(&mut self, w: &mut W) -> std::io::Result<()> {
+        self.end(w, b"]")
+    }
 
-    let bin = base64::decode(dat)?;
-    if bin.len() != 64 {
-        bail!("Invalid secret key length");
+    fn begin_array_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        w: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        self.value(w, first)
     }
 
-    return Ok(Keys {
-        host,
-        keys: Keypair {
-            secret: SecretKey::from_bytes(&bin[0..32])?,
-            public: PublicKey::from_bytes(&bin[32..])?,
-        },
-    });
-}
+    fn begin_object<W: ?Sized + std::io::Write>(&mut self, w: &mut W) -> std::io::Result<()> {
+        self.begin(w, b"{")
+    }
 
-pub fn sk_to_pk(sk: &str) -> Result<String> {
-    let pieces: Vec<&str> = sk.split(":").collect();
-    if pieces.len() != 2 {
-        bail!("invalid sk: expected to contain hostname");
+    fn end_object<W: ?Sized + std::io::Write>(&mut self, w: &mut W) -> std::io::Result<()> {
+        self.end(w, b"}")
+    }
+
+    fn begin_object_key<W: ?Sized + std::io::Write>(
+        &mut self,
+        w: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        self.value(w, first)
     }
 
-    let kp = sk_to_keypair(sk)?;
-    let pk = kp.keys.public.to_bytes();
-    Ok(format!("{}:{}", kp.host, base64::encode(pk)))
+    fn begin_object_value<W: ?Sized + std::io::Write>(&mut self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(b": ")
+    }
 }
 
-pub fn sign_narinfo(store: &Store, ks: &Keys, body: &str) -> Result<String> {
-    let mut ls = HashMap::new();
-    for x in body.lines() {
-        let x = x.trim();
-        if x == "" {
-            continue;
-        }
-        let (k, v) = split_once(x);
-        let (k, v) = (k.trim(), v.trim());
+/// Convert a narinfo to a canonical JSON form, so that the hash of the JSON
+/// itself is stable, e.g. to use as a key when deduplicating across caches.
+/// Object keys and `References` are sorted, numbers are normalized, and there
+/// is no whitespace at all.
+pub fn narinfo_to_canonical_json(body: &str, warnings: &mut Warnings) -> Result<String> {
+    let fields = narinfo_to_json_map(body, &JsonOptions::default(), warnings)?;
+    if fields.is_empty() {
+        bail!("no fields found");
+    }
 
-        let accepted = vec!["StorePath", "NarHash", "NarSize", "References"];
-        if !accepted.contains(&k) {
-            continue;
+    let mut json = serde_json::Value::Object(fields);
+    if let Some(refs) = json.get_mut("References").and_then(|r| r.as_array_mut()) {
+        refs.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+    }
+    Ok(canonicalize(json).to_string())
+}
+
+/// Rebuild every object in some JSON with its keys in sorted order, which
+/// doesn't rely on how `serde_json` happens to be storing them.
+fn canonicalize(json: serde_json::Value) -> serde_json::Value {
+    match json {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+                .into_iter()
+                .map(|(k, v)| (k, canonicalize(v)))
+                .collect::<serde_json::Map<_, _>>()
+                .into()
         }
-        ls.insert(k, v);
+        serde_json::Value::Array(xs) => xs.into_iter().map(canonicalize).collect(),
+        x => x,
     }
+}
 
-    let path: &str = ls.get("StorePath").expect("no StorePath found");
-    let hash: &str = ls.get("NarHash").expect("no NarHash found");
-    let size: u64 = ls.get("NarSize").expect("no NarSize found").parse()?;
-    let refs0: Vec<String> = ls
-        .get("References")
-        .expect("no References found")
-        .split_whitespace()
-        .map(|x| format!("{}/{}", store.store_path, x))
-        .collect();
-    // putting both of these in the same iteration causes capture errors which
-    // i'm too inexperienced to solve gracefully, yet
-    let refs: Vec<&str> = refs0.iter().map(|x| x.as_str()).collect();
+/// Split a string into two strings at the first colon `:` character, or
+/// `None` if there isn't one, e.g. for a stray line in a truncated download.
+///
+/// Any remaining encounters are ignored and remain part of the second string.
+/// The colon character is removed.
+fn split_once(in_string: &str) -> Option<(&str, &str)> {
+    in_string.split_once(':')
+}
 
-    let fp = store.fingerprint_path(path, hash, &size, refs)?;
-    let sig = base64::encode(ks.keys.sign(fp.as_bytes()).to_bytes());
-    Ok(format!("{}:{}", ks.host, sig))
+/// Split the value of a `Sig` field into its `host:signature` components,
+/// failing if either of them is missing or empty.
+fn parse_sig(v: &str) -> Result<(&str, &str)> {
+    let (host, sig) = match v.split_once(':') {
+        Some((host, sig)) => (host.trim(), sig.trim()),
+        None => bail!("signature has no host: {}", v),
+    };
+    if host.is_empty() {
+        bail!("signature has an empty host: {}", v);
+    }
+    if sig.is_empty() {
+        bail!("signature for {} is empty", host);
+    }
+    Ok((host, sig))
 }
 
-pub struct Store {
-    store_path: String,
+/// Lowercase the algorithm name of a `algo:hash` string, e.g. `SHA256:...`
+/// becomes `sha256:...`. The hash itself is left alone, since base32 and
+/// base64 hashes are case sensitive.
+pub fn normalize_hash_algo(hash: &str) -> String {
+    match hash.split_once(':') {
+        Some((algo, h)) => format!("{}:{}", algo.to_ascii_lowercase(), h),
+        None => hash.to_string(),
+    }
 }
 
-impl Store {
-    pub fn new(store_path: &str) -> Result<Store> {
-        Ok(Store {
-            store_path: store_path.to_string(),
+/// Decode a base16 string, e.g. the hash part of a base16 `NarHash`.
+fn decode_base16(s: &str) -> Result<Vec<u8>> {
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("invalid base16 string: {}", s);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| match s.get(i..i + 2) {
+            Some(b) => Ok(u8::from_str_radix(b, 16)?),
+            None => bail!("base16 string has an odd length"),
         })
+        .collect()
+}
+
+/// Split an SRI hash, e.g. `sha256-<base64>`, into its algorithm and the
+/// decoded hash. Anything that isn't SRI, like any `algo:hash`, is `None`.
+fn split_sri(hash: &str) -> Option<Result<(String, Vec<u8>)>> {
+    if hash.contains(':') {
+        return None;
     }
+    let (algo, b64) = hash.split_once('-')?;
+    let algo = algo.to_ascii_lowercase();
+    hash_size(&algo)?;
+    Some(match base64::decode(b64) {
+        Ok(bytes) => Ok((algo, bytes)),
+        Err(e) => Err(anyhow::anyhow!("invalid SRI hash {}: {}", hash, e)),
+    })
+}
 
-    pub fn fingerprint_path<'a, I>(
-        &self,
-        path: &str,
-        hash: &str,
-        size: &u64,
-        refs: I,
-    ) -> anyhow::Result<String>
-    where
-        I: IntoIterator<Item = &'a str>,
-    {
-        // https://github.com/NixOS/nix/blob/2.10.3/perl/lib/Nix/Manifest.pm#L234
+/// The size in bytes of a hash made with `algo`, for the algorithms Nix
+/// knows about.
+fn hash_size(algo: &str) -> Option<usize> {
+    match algo {
+        "md5" => Some(16),
+        "sha1" => Some(20),
+        "sha256" => Some(32),
+        "sha512" => Some(64),
+        _ => None,
+    }
+}
 
-        if !path.starts_with(&self.store_path) {
-            bail!("path must start with store path");
+/// Convert a base16 hash, or an SRI hash, into the `algo:base32` form used by
+/// Nix itself. Any other hash, including one which is already base32, is left
+/// alone.
+fn base32_hash(hash: &str) -> Result<String> {
+    if let Some(sri) = split_sri(hash) {
+        let (algo, bytes) = sri?;
+        return Ok(format!("{}:{}", algo, base32::encode(&bytes)));
+    }
+    match hash.split_once(':') {
+        Some((algo, h)) if hash_size(algo).map(|n| n * 2) == Some(h.len()) => {
+            Ok(format!("{}:{}", algo, base32::encode(&decode_base16(h)?)))
         }
+        _ => Ok(hash.to_string()),
+    }
+}
 
-        if !hash.starts_with("sha256:") {
-            bail!("hash must be sha256");
+/// Rewrite any base16 or SRI `FileHash` or `NarHash` in a narinfo into the
+/// canonical base32 form, leaving every other line as it was. The signatures
+/// are made over the base32 form anyway, so they're still valid, but they're
+/// dropped unless `keep_sigs` is set, in case anything compares the narinfo
+/// text.
+pub fn normalize_hashes(body: &str, keep_sigs: bool) -> Result<String> {
+    let mut out = String::with_capacity(body.len());
+    for x in body.lines() {
+        match split_once(x) {
+            Some((k, v)) if ["FileHash", "NarHash"].contains(&k.trim()) => {
+                let hash = base32_hash(&normalize_hash_algo(v.trim()))?;
+                out.push_str(&format!("{}: {}", k.trim(), hash));
+            }
+            Some((k, _)) if k.trim() == "Sig" && !keep_sigs => continue,
+            _ => out.push_str(x),
         }
+        out.push('\n');
+    }
+    Ok(out)
+}
 
-        if hash.len() == 71 {
-            // XXX FIXME: convert base16 to base32
-            bail!("base16 hashes currently not supported");
-        }
+/// Turn the hash in a `CA` field, which is normally base32, into base16.
+fn ca_hash_to_base16(algo: &str, hash: &str) -> Result<String> {
+    let len = match hash_size(algo) {
+        Some(len) => len,
+        None => bail!("unknown hash algorithm: {}", algo),
+    };
+    let bytes = if hash.len() == len * 2 {
+        decode_base16(hash)?
+    } else {
+        base32::decode(hash)?
+    };
+    if bytes.len() != len {
+        bail!("invalid {} hash length: {}", algo, hash);
+    }
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
 
-        if hash.len() != 59 {
-            bail!("invalid hash length (not 59)");
-        }
+/// Find the value of the first field named `key` in a narinfo body, if any.
+pub fn find_field<'a>(body: &'a str, key: &str) -> Option<&'a str> {
+    body.lines()
+        .filter_map(|x| x.split_once(':'))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim())
+}
 
-        let valid: Result<Vec<&'a str>, _> = refs
-            .into_iter()
-            .map(|p| {
-                if !p.starts_with(&self.store_path) {
-                    bail!("ref must start with store path");
-                } else {
-                    Ok(p)
-                }
-            })
-            .collect();
-        let refs = valid.unwrap().join(",");
+/// The default limit on the number of `References` in a narinfo; there are
+/// real packages with thousands, but nothing anywhere close to this.
+pub const DEFAULT_MAX_REFERENCES: usize = 100_000;
 
-        Ok(format!("1;{};{};{};{}", path, hash, size, refs))
+/// The extension that Nix gives a NAR compressed with `compression`.
+fn compression_extension(compression: &str) -> Option<&'static str> {
+    match compression {
+        "none" => Some(".nar"),
+        "xz" => Some(".nar.xz"),
+        "bzip2" => Some(".nar.bz2"),
+        "zstd" => Some(".nar.zst"),
+        "gzip" => Some(".nar.gz"),
+        "br" => Some(".nar.br"),
+        "lz4" => Some(".nar.lz4"),
+        _ => None,
     }
 }
 
-// ---------------------------------------------------------------------------------------------------------------------
-
-#[cfg(test)]
-mod tests {
-    use super::sk_to_keypair;
-    use crate::narinfo::{sign_narinfo, sk_to_pk, Store};
+/// Fail if a narinfo has more than `max` references. This only counts them,
+/// so it's cheap to do before anything goes and allocates a path for each
+/// one, which matters when a server is handed a narinfo that some malicious
+/// client stuffed with millions of them.
+pub fn check_max_references(body: &str, max: usize) -> Result<()> {
+    for refs in query_field(body, "References") {
+        if refs.split_whitespace().take(max + 1).count() > max {
+            bail!("too many references (more than {})", max);
+        }
+    }
+    Ok(())
+}
 
-    #[test]
-    fn test_narinfo_to_json() {
-        let input = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
-URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
-Compression: xz
-FileHash: sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds
-FileSize: 3542408
-NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
-NarSize: 17680416
-References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib 20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2 9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11 ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11 dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16 dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15 fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3 hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10 klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04 q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117 sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08
-Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv
-Sig: cache.nixos.org-1:eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA=="#;
-        let expected = r#"{ "StorePath": "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15", "URL": "nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz", "Compression": "xz", "FileHash": { "type": "sha256", "hash": "06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds" }, "FileSize": 3542408, "NarHash": { "type": "sha256", "hash": "1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3" }, "NarSize": 17680416, "References": [ "18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib", "20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2", "9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11", "ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11", "dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16", "dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15", "fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3", "hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10", "klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04", "q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117", "sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev", "yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08" ], "Deriver": "x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv", "Sig": { "cache.nixos.org-1": "eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA==" } }"#;
+/// Fail if any field isn't written exactly as `Key: value`, with one space
+/// after the colon and none anywhere else, since the other functions here
+/// would just trim it but the file wouldn't hash the same as a canonical
+/// one. An empty value is fine, as Nix writes `References: ` like that.
+pub fn check_strict_colons(body: &str) -> Result<()> {
+    for line in body.lines().filter(|l| !l.trim().is_empty()) {
+        let canonical = match split_once(line) {
+            Some((k, v)) => match v.strip_prefix(' ') {
+                Some(v) => !k.is_empty() && k.trim() == k && v.trim() == v,
+                None => false,
+            },
+            // there's no colon to be strict about
+            None => true,
+        };
+        if !canonical {
+            bail!(ParseError::BadSpacing {
+                line: line.to_string()
+            });
+        }
+    }
+    Ok(())
+}
 
-        let mut output = String::new();
-        crate::narinfo::narinfo_to_json(input.to_string(), &mut output);
-        assert_eq!(expected.trim(), output.trim());
+/// Fail if any line of a narinfo is longer than `max` bytes, so that a server
+/// can turn away a single enormous garbage line before anything parses it.
+/// Note that a long `References` is legitimately a long line, too.
+pub fn check_max_line_length(body: &str, max: usize) -> Result<()> {
+    match body.lines().position(|l| l.len() > max) {
+        Some(n) => Err(line_too_long(n + 1, max)),
+        None => Ok(()),
     }
+}
 
-    #[test]
-    fn test_fingerprint_path() {
-        let path = "/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1";
-        let hash = "sha256:0si0g30ksvlz953ysczn7jb0z942xzhrzwzx6h94f76r9k8269ph";
-        let size = 64184;
-        let refs = vec![
-            "/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1",
-            "/nix/store/d2bpliayddadf6lx6l1i04w265gqw8n6-glibc-2.34-210",
-        ];
+fn line_too_long(line: usize, max: usize) -> anyhow::Error {
+    anyhow::anyhow!("line {} is too long (more than {} bytes)", line, max)
+}
 
-        let expected = "1;/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1;sha256:0si0g30ksvlz953ysczn7jb0z942xzhrzwzx6h94f76r9k8269ph;64184;/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1,/nix/store/d2bpliayddadf6lx6l1i04w265gqw8n6-glibc-2.34-210";
+/// Fail if any of the given fields is missing from a narinfo, e.g. to insist
+/// on `References` before signing something. Like [`query_field`], known
+/// names are case insensitive.
+pub fn check_required_fields(body: &str, fields: &[String]) -> Result<()> {
+    for k in fields {
+        if query_field(body, k).is_empty() {
+            bail!(ParseError::MissingField {
+                name: canonical_field(k).unwrap_or(k).to_string()
+            });
+        }
+    }
+    Ok(())
+}
 
-        let s = Store::new("/nix/store").unwrap();
-        assert_eq!(
-            s.fingerprint_path(&path, &hash, &size, refs).unwrap(),
-            expected.to_string()
-        );
+/// Fail if any field other than `Sig` occurs more than once. Everything else
+/// here just takes the last one, but a narinfo with two `NarHash` lines is
+/// corrupt, and which one is right can't be known.
+pub fn check_duplicate_fields(body: &str) -> Result<()> {
+    let mut seen = HashSet::new();
+    for (k, _) in body.lines().filter_map(split_once) {
+        let k = k.trim();
+        if k != "Sig" && !seen.insert(k) {
+            bail!(ParseError::DuplicateField {
+                name: k.to_string()
+            });
+        }
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_secretkey_to_publickey() {
-        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
-        let pk = "t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=";
+// ---------------------------------------------------------------------------------------------------------------------
 
-        assert_eq!(sk_to_pk(sk).unwrap(), pk.to_string());
-    }
+/// A typed representation of a `narinfo` file.
+///
+/// Like in the narinfo files themselves, `references` and `deriver` are
+/// relative to the store, i.e. they're just `<hash>-<name>` without the store
+/// directory in front.
+///
+/// Converting to JSON and signing work on the text instead: a `NarInfo` needs
+/// a `URL`, which signing doesn't, and loses the order of the fields, which
+/// the JSON keeps. Its [`fingerprint`](NarInfo::fingerprint) is still made the
+/// same way as the text's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarInfo {
+    pub store_path: String,
+    pub url: String,
+    pub compression: Option<String>,
+    pub file_hash: Option<String>,
+    pub file_size: Option<u64>,
+    pub nar_hash: String,
+    pub nar_size: u64,
+    pub references: Vec<String>,
+    pub deriver: Option<String>,
+    pub sigs: Vec<(String, String)>,
+    /// Any fields that aren't otherwise understood.
+    pub extra: HashMap<String, String>,
+}
+
+impl std::fmt::Display for NarInfo {
+    /// Render the narinfo as text, with the fields in the same order that Nix
+    /// itself writes them.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "StorePath: {}", self.store_path)?;
+        writeln!(f, "URL: {}", self.url)?;
+        if let Some(compression) = &self.compression {
+            writeln!(f, "Compression: {}", compression)?;
+        }
+        if let Some(file_hash) = &self.file_hash {
+            writeln!(f, "FileHash: {}", file_hash)?;
+        }
+        if let Some(file_size) = self.file_size {
+            writeln!(f, "FileSize: {}", file_size)?;
+        }
+        writeln!(f, "NarHash: {}", self.nar_hash)?;
+        writeln!(f, "NarSize: {}", self.nar_size)?;
+        writeln!(f, "References: {}", self.references.join(" "))?;
+        if let Some(deriver) = &self.deriver {
+            writeln!(f, "Deriver: {}", deriver)?;
+        }
+        for (host, sig) in &self.sigs {
+            writeln!(f, "Sig: {}:{}", host, sig)?;
+        }
+
+        // sort these so that the output is at least stable
+        let mut extra: Vec<_> = self.extra.iter().collect();
+        extra.sort();
+        for (k, v) in extra {
+            writeln!(f, "{}: {}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for NarInfo {
+    type Err = ParseError;
+
+    /// Parse the text of a narinfo. Fields that aren't understood end up in
+    /// [`extra`](NarInfo::extra).
+    fn from_str(body: &str) -> Result<NarInfo, ParseError> {
+        let mut builder = NarInfoBuilder::new();
+        for x in body.lines() {
+            let x = x.trim();
+            if x.is_empty() {
+                continue;
+            }
+            // skip over anything that isn't a field, rather than giving up on
+            // the whole narinfo
+            let (k, v) = match split_once(x) {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => continue,
+            };
+
+            builder = match k {
+                "StorePath" => builder.store_path(v),
+                "URL" => builder.url(v),
+                "Compression" => builder.compression(v),
+                "FileHash" => builder.file_hash(v),
+                "FileSize" => builder.file_size(parse_number(k, v)?),
+                "NarHash" => builder.nar_hash(v),
+                "NarSize" => builder.nar_size(parse_number(k, v)?),
+                "References" => {
+                    // like any other field, a later References replaces an
+                    // earlier one, rather than adding to it
+                    builder.references.clear();
+                    v.split_whitespace().fold(builder, |b, r| b.reference(r))
+                }
+                "Deriver" => builder.deriver(v),
+                "Sig" => match parse_sig(v) {
+                    Ok((host, sig)) => builder.sig(host, unwrap_base64(sig)),
+                    Err(_) => return Err(ParseError::BadSig { sig: v.to_string() }),
+                },
+                _ => builder.extra(k, v),
+            };
+        }
+        builder.build()
+    }
+}
+
+fn parse_number(field: &str, value: &str) -> Result<u64, ParseError> {
+    value.parse().map_err(|_| ParseError::BadNumber {
+        field: field.to_string(),
+        value: value.to_string(),
+    })
+}
+
+impl NarInfo {
+    /// The `References`, as absolute paths inside of the given store.
+    pub fn references_absolute(&self, store: &Store) -> Result<Vec<String>> {
+        self.references
+            .iter()
+            .map(|r| store.absolute_path(r))
+            .collect()
+    }
+
+    /// Compute the fingerprint of this narinfo, i.e. the string which its
+    /// signatures are made over. This is made exactly the same way as
+    /// [`fingerprint_narinfo`] makes it from the text.
+    pub fn fingerprint(&self, store: &Store) -> Result<Fingerprint> {
+        let fields = SignedFields {
+            path: &self.store_path,
+            hash: &self.nar_hash,
+            size: self.nar_size,
+            refs: self.references.iter().map(|r| r.as_str()).collect(),
+        };
+        fields.fingerprint(store)
+    }
+
+    /// Move this narinfo from one store to another, e.g. when migrating a
+    /// cache away from `/nix/store`. The `StorePath` and any absolute
+    /// `References` or `Deriver` are rewritten, while relative ones stay as
+    /// they are; either way the fingerprint changes, so the signatures are
+    /// all dropped.
+    pub fn rewrite_store_dir(&mut self, from: &Store, to: &Store) -> Result<()> {
+        let rewrite = |p: &str| -> Result<String> {
+            if p.starts_with('/') {
+                to.absolute_path(from.relative_path(p)?)
+            } else {
+                Ok(p.to_string())
+            }
+        };
+        self.store_path = to.absolute_path(from.relative_path(&self.store_path)?)?;
+        self.references = self
+            .references
+            .iter()
+            .map(|r| rewrite(r))
+            .collect::<Result<_>>()?;
+        self.deriver = self.deriver.as_deref().map(rewrite).transpose()?;
+        self.sigs.clear();
+        Ok(())
+    }
+}
+
+/// A builder for constructing a [`NarInfo`] field by field.
+#[derive(Debug, Default)]
+pub struct NarInfoBuilder {
+    store_path: Option<String>,
+    url: Option<String>,
+    compression: Option<String>,
+    compression_default: Option<String>,
+    file_hash: Option<String>,
+    file_size: Option<u64>,
+    nar_hash: Option<String>,
+    nar_size: Option<u64>,
+    references: Vec<String>,
+    deriver: Option<String>,
+    sigs: Vec<(String, String)>,
+    extra: HashMap<String, String>,
+}
+
+impl NarInfoBuilder {
+    pub fn new() -> NarInfoBuilder {
+        NarInfoBuilder::default()
+    }
+
+    pub fn store_path(mut self, store_path: impl Into<String>) -> NarInfoBuilder {
+        self.store_path = Some(store_path.into());
+        self
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> NarInfoBuilder {
+        self.url = Some(url.into());
+        self
+    }
+
+    pub fn compression(mut self, compression: impl Into<String>) -> NarInfoBuilder {
+        self.compression = Some(compression.into());
+        self
+    }
+
+    /// Set the compression to use if [`compression`](Self::compression) is
+    /// never given, e.g. `zstd` when building narinfos for a zstd cache.
+    pub fn compression_default(mut self, compression: impl Into<String>) -> NarInfoBuilder {
+        self.compression_default = Some(compression.into());
+        self
+    }
+
+    pub fn file_hash(mut self, file_hash: impl Into<String>) -> NarInfoBuilder {
+        self.file_hash = Some(file_hash.into());
+        self
+    }
+
+    pub fn file_size(mut self, file_size: u64) -> NarInfoBuilder {
+        self.file_size = Some(file_size);
+        self
+    }
+
+    pub fn nar_hash(mut self, nar_hash: impl Into<String>) -> NarInfoBuilder {
+        self.nar_hash = Some(nar_hash.into());
+        self
+    }
+
+    pub fn nar_size(mut self, nar_size: u64) -> NarInfoBuilder {
+        self.nar_size = Some(nar_size);
+        self
+    }
+
+    /// Add a single (relative) reference.
+    pub fn reference(mut self, reference: impl Into<String>) -> NarInfoBuilder {
+        self.references.push(reference.into());
+        self
+    }
+
+    pub fn deriver(mut self, deriver: impl Into<String>) -> NarInfoBuilder {
+        self.deriver = Some(deriver.into());
+        self
+    }
+
+    /// Add a signature from the given host.
+    pub fn sig(mut self, host: impl Into<String>, sig: impl Into<String>) -> NarInfoBuilder {
+        self.sigs.push((host.into(), sig.into()));
+        self
+    }
+
+    /// Add some other field which isn't otherwise understood.
+    pub fn extra(mut self, key: impl Into<String>, value: impl Into<String>) -> NarInfoBuilder {
+        self.extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// Build the [`NarInfo`], failing if any of the fields Nix requires are
+    /// missing.
+    pub fn build(self) -> Result<NarInfo, ParseError> {
+        Ok(NarInfo {
+            store_path: required("StorePath", self.store_path)?,
+            url: required("URL", self.url)?,
+            compression: self.compression.or(self.compression_default),
+            file_hash: self.file_hash,
+            file_size: self.file_size,
+            nar_hash: required("NarHash", self.nar_hash)?,
+            nar_size: required("NarSize", self.nar_size)?,
+            references: self.references,
+            deriver: self.deriver,
+            sigs: self.sigs,
+            extra: self.extra,
+        })
+    }
+}
+
+fn required<T>(name: &str, field: Option<T>) -> Result<T, ParseError> {
+    field.ok_or_else(|| ParseError::MissingField {
+        name: name.to_string(),
+    })
+}
+
+/// Convert the output of `nix path-info --json` into narinfos, one for each
+/// path. Both the older form (an array of objects) and the newer one (an
+/// object keyed by store path) are understood. Paths from a local store have
+/// no `url`, so they get the one an uncompressed upload would have.
+pub fn path_info_to_narinfos(json: &str) -> Result<Vec<NarInfo>> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    match &value {
+        serde_json::Value::Array(infos) => infos
+            .iter()
+            .map(|info| path_info_to_narinfo(None, info))
+            .collect(),
+        serde_json::Value::Object(infos) => infos
+            .iter()
+            .map(|(path, info)| path_info_to_narinfo(Some(path), info))
+            .collect(),
+        _ => bail!("expected an array or object of path infos"),
+    }
+}
+
+fn path_info_to_narinfo(path: Option<&str>, info: &serde_json::Value) -> Result<NarInfo> {
+    let path = match path.or_else(|| info["path"].as_str()) {
+        Some(path) => path,
+        None => bail!("missing required field: path"),
+    };
+    if info.is_null() {
+        bail!("path is not valid: {}", path);
+    }
+
+    // everything in the JSON is absolute, but narinfos want the references
+    // and deriver relative to the store the path is in
+    let store = Store::from_store_path(path)?;
+    let mut builder = NarInfoBuilder::new().store_path(path);
+
+    if let Some(v) = info["url"].as_str() {
+        builder = builder.url(v);
+    }
+    if let Some(v) = info["compression"].as_str() {
+        builder = builder.compression(v);
+    }
+    // newer versions of nix give SRI hashes, but narinfos always have base32
+    if let Some(v) = info["downloadHash"].as_str() {
+        builder = builder.file_hash(base32_hash(&normalize_hash_algo(v))?);
+    }
+    if let Some(v) = info["downloadSize"].as_u64() {
+        builder = builder.file_size(v);
+    }
+    if let Some(v) = info["narHash"].as_str() {
+        builder = builder.nar_hash(base32_hash(&normalize_hash_algo(v))?);
+    }
+    if let Some(v) = info["narSize"].as_u64() {
+        builder = builder.nar_size(v);
+    }
+    for r in info["references"].as_array().into_iter().flatten() {
+        match r.as_str() {
+            Some(r) => builder = builder.reference(store.relative_path(r)?),
+            None => bail!("invalid reference: {}", r),
+        }
+    }
+    if let Some(v) = info["deriver"].as_str() {
+        builder = builder.deriver(store.relative_path(v)?);
+    }
+    for sig in info["signatures"].as_array().into_iter().flatten() {
+        match sig.as_str().map(parse_sig) {
+            Some(Ok((host, sig))) => builder = builder.sig(host, sig),
+            _ => bail!("invalid signature: {}", sig),
+        }
+    }
+    if let Some(v) = info["ca"].as_str() {
+        builder = builder.extra("CA", v);
+    }
+
+    // the path info of a local store has no url, since the NAR isn't in any
+    // cache yet, so name it the way an uncompressed upload of it would be
+    if builder.nar_hash.is_none() {
+        bail!("missing required field: narHash");
+    }
+    if builder.url.is_none() {
+        let compression = builder.compression.clone().unwrap_or_else(|| "none".into());
+        let file_hash = match compression.as_str() {
+            "none" => builder
+                .file_hash
+                .clone()
+                .or_else(|| builder.nar_hash.clone()),
+            _ => builder.file_hash.clone(),
+        };
+        builder = builder
+            .url(nar_url(Some(&compression), file_hash.as_deref())?)
+            .compression(compression);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// The opposite of [`narinfo_to_json`]: turn a JSON object of the same shape
+/// back into a narinfo. `References` and the `Deriver` may be relative or
+/// absolute (as with `--abs-refs` or `--refs-detailed`), and `Sig` may be
+/// grouped by validity, since all of that is just undone again here.
+pub fn json_to_narinfo(json: &str) -> Result<NarInfo> {
+    json_to_narinfo_with(json, false)
+}
+
+/// Like [`json_to_narinfo`], but if `synthesize_url` is set and the JSON has
+/// no `URL`, make one up from the `FileHash` and `Compression`, the same way
+/// Nix names the NARs it uploads: `nar/<hash>.nar.xz` and so on.
+pub fn json_to_narinfo_with(json: &str, synthesize_url: bool) -> Result<NarInfo> {
+    use serde_json::Value;
+
+    let value: Value = serde_json::from_str(json)?;
+    let fields = match value.as_object() {
+        Some(fields) => fields,
+        None => bail!("expected a JSON object"),
+    };
+    let store = match fields.get("StorePath").and_then(|v| v.as_str()) {
+        Some(path) => Store::from_store_path(path)?,
+        None => bail!("missing required field: StorePath"),
+    };
+    let relative = |p: &str| -> Result<String> {
+        if p.starts_with('/') {
+            Ok(store.relative_path(p)?.to_string())
+        } else {
+            Ok(p.to_string())
+        }
+    };
+    // anything else that's simple enough to have a single text form
+    let text = |k: &str, v: &Value| match v {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(if *b { "1" } else { "0" }.to_string()),
+        Value::Array(xs) => xs
+            .iter()
+            .map(|x| match x.as_str() {
+                Some(x) => Ok(x),
+                None => bail!("invalid value for {}: {}", k, x),
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|xs| xs.join(" ")),
+        _ => bail!("invalid value for {}: {}", k, v),
+    };
+    let hash = |k: &str, v: &Value| match (v["type"].as_str(), v["hash"].as_str()) {
+        (Some(typ), Some(hash)) => Ok(format!("{}:{}", typ, hash)),
+        _ => text(k, v),
+    };
+    let number = |k: &str, v: &Value| match v.as_u64() {
+        Some(n) => Ok(n),
+        None => match text(k, v)?.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => bail!("{} is not a number: {}", k, v),
+        },
+    };
+
+    let mut builder = NarInfoBuilder::new();
+    for (k, v) in fields.iter() {
+        builder = match k.as_str() {
+            "StorePath" => builder.store_path(text(k, v)?),
+            "URL" => builder.url(text(k, v)?),
+            "Compression" => builder.compression(text(k, v)?),
+            "FileHash" => builder.file_hash(hash(k, v)?),
+            "FileSize" => builder.file_size(number(k, v)?),
+            "NarHash" => builder.nar_hash(hash(k, v)?),
+            k if is_hash_field(k) => builder.extra(k, hash(k, v)?),
+            "NarSize" => builder.nar_size(number(k, v)?),
+            "CA" => match (v["method"].as_str(), v["hash"].as_str()) {
+                (Some(method), Some(hash)) => builder.extra(k, format!("{}:{}", method, hash)),
+                _ => builder.extra(k, text(k, v)?),
+            },
+            "References" => {
+                for r in v.as_array().into_iter().flatten() {
+                    let r = match (r.as_str(), r["path"].as_str()) {
+                        (Some(r), _) | (None, Some(r)) => relative(r)?,
+                        _ => match (r["hash"].as_str(), r["name"].as_str()) {
+                            (Some(hash), Some(name)) => format!("{}-{}", hash, name),
+                            _ => bail!("invalid reference: {}", r),
+                        },
+                    };
+                    builder = builder.reference(r);
+                }
+                builder
+            }
+            "Deriver" if v.is_null() => builder.deriver(UNKNOWN_DERIVER),
+            "Deriver" => builder.deriver(relative(&text(k, v)?)?),
+            "Sig" => {
+                // either host => sig, or grouped by validity
+                let groups = match (v.get("valid"), v.get("invalid")) {
+                    (Some(valid), Some(invalid)) => vec![valid, invalid],
+                    _ => vec![v],
+                };
+                for (host, sig) in groups.into_iter().filter_map(|g| g.as_object()).flatten() {
+                    match sig.as_str() {
+                        Some(sig) => builder = builder.sig(host, sig),
+                        None => bail!("invalid signature for {}: {}", host, sig),
+                    }
+                }
+                builder
+            }
+            // these are only ever computed from the rest of the narinfo
+            "Verification" | "ContentAddressed" => builder,
+            _ => builder.extra(k, text(k, v)?),
+        };
+    }
+
+    if synthesize_url && builder.url.is_none() {
+        let url = nar_url(builder.compression.as_deref(), builder.file_hash.as_deref())?;
+        builder = builder.url(url);
+    }
+    Ok(builder.build()?)
+}
+
+/// Make up the URL of a NAR from its `Compression` and `FileHash`, the same
+/// way Nix names the NARs it uploads: `nar/<hash>.nar.xz` and so on.
+fn nar_url(compression: Option<&str>, file_hash: Option<&str>) -> Result<String> {
+    let ext = match compression {
+        Some(c) => match compression_extension(c) {
+            Some(ext) => ext,
+            None => bail!("can't synthesize a URL for Compression {}", c),
+        },
+        None => bail!("can't synthesize a URL without a Compression"),
+    };
+    let hash = match file_hash {
+        Some(hash) => base32_hash(&normalize_hash_algo(hash))?,
+        None => bail!("can't synthesize a URL without a FileHash"),
+    };
+    let hash = hash.split_once(':').map_or(hash.as_str(), |(_, h)| h);
+    Ok(format!("nar/{}{}", hash, ext))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// A field which has a different value in two narinfos. A missing field has
+/// no value at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub field: String,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+/// Compare two narinfos field by field, returning every field that differs,
+/// sorted by name.
+///
+/// Fields are compared semantically rather than as text: `References` are
+/// compared as a set of `<hash>-<name>`s, regardless of their order or
+/// whether they're absolute or relative, and `Sig`s are compared as a set.
+pub fn diff_narinfos(left: &str, right: &str) -> Vec<FieldDiff> {
+    let (left, right) = (diff_fields(left), diff_fields(right));
+
+    let mut names: Vec<&String> = left.keys().chain(right.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter(|k| left.get(*k) != right.get(*k))
+        .map(|k| FieldDiff {
+            field: k.clone(),
+            left: left.get(k).cloned(),
+            right: right.get(k).cloned(),
+        })
+        .collect()
+}
+
+/// Collect the fields of a narinfo into a normalized form for comparison.
+fn diff_fields(body: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut sigs = Vec::new();
+    for x in body.lines() {
+        let (k, v) = match x.split_once(':') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+
+        match k {
+            "References" => {
+                let mut refs: Vec<&str> = v
+                    .split_whitespace()
+                    .map(|r| r.rsplit('/').next().unwrap_or(r))
+                    .collect();
+                refs.sort_unstable();
+                refs.dedup();
+                fields.insert(k.to_string(), refs.join(" "));
+            }
+            "Sig" => sigs.push(v),
+            _ => {
+                fields.insert(k.to_string(), v.to_string());
+            }
+        }
+    }
+
+    if !sigs.is_empty() {
+        sigs.sort_unstable();
+        fields.insert("Sig".to_string(), sigs.join(" "));
+    }
+    fields
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct Keys {
+    pub host: String,
+    pub keys: Keypair,
+}
+
+impl Keys {
+    /// Generate a brand new keypair for the given host, using the operating
+    /// system's randomness.
+    pub fn generate(host: &str) -> Keys {
+        Keys {
+            host: host.to_string(),
+            keys: Keypair::generate(&mut OsRng),
+        }
+    }
+
+    /// The secret key, in the same `host:base64` form as `NIX_SIGNING_KEY`.
+    pub fn secret_key(&self) -> String {
+        format!("{}:{}", self.host, base64::encode(self.keys.to_bytes()))
+    }
+
+    /// A `sha256:` hash of the secret key, which identifies it without giving
+    /// it away, e.g. to log which key a server has loaded.
+    pub fn secret_key_digest(&self) -> String {
+        let hash = Sha256::digest(self.keys.secret.as_bytes());
+        format!("sha256:{}", base32::encode(&hash))
+    }
+
+    /// The public key, in the `host:base64` form that Nix expects.
+    pub fn public_key(&self) -> String {
+        format!(
+            "{}:{}",
+            self.host,
+            base64::encode(self.keys.public.to_bytes())
+        )
+    }
+}
+
+pub fn sk_to_keypair(sk: &str) -> Result<Keys> {
+    let pieces: Vec<&str> = sk.split(":").collect();
+    if pieces.len() != 2 {
+        bail!("invalid sk: expected to contain hostname");
+    }
+
+    let host = pieces[0];
+    let dat = pieces[1];
+
+    let bin = decode_base64(dat)?;
+    if bin.len() != 64 {
+        bail!("Invalid secret key length");
+    }
+    keypair_from_bytes(host, &bin)
+}
+
+/// Derive a keypair from a 32-byte seed, rather than from the operating
+/// system's randomness, so that tests and deployments can get the same key
+/// every time. Anyone with the seed has the secret key, of course.
+pub fn keypair_from_seed(host: &str, seed: &[u8; 32]) -> Result<Keys> {
+    let secret = SecretKey::from_bytes(seed)?;
+    let public = PublicKey::from(&secret);
+    Ok(Keys {
+        host: host.to_string(),
+        keys: Keypair { secret, public },
+    })
+}
+
+/// Parse a seed for [`keypair_from_seed`] from 64 base16 digits.
+pub fn parse_seed(hex: &str) -> Result<[u8; 32]> {
+    let bytes = decode_base16(hex.trim())?;
+    match bytes.try_into() {
+        Ok(seed) => Ok(seed),
+        Err(bytes) => bail!("seed must be 32 bytes, not {}", bytes.len()),
+    }
+}
+
+/// Build a keypair from 64 bytes of raw key material, i.e. the secret key
+/// followed by the public key, just like the base64 part of `host:base64`.
+/// The public half has to be the one that belongs to the secret half, or
+/// every signature made with the keypair would fail to verify against it.
+fn keypair_from_bytes(host: &str, bin: &[u8]) -> Result<Keys> {
+    let secret = SecretKey::from_bytes(&bin[0..32])?;
+    let public = PublicKey::from_bytes(&bin[32..64])?;
+    if PublicKey::from(&secret) != public {
+        bail!("secret key doesn't match its public key");
+    }
+    Ok(Keys {
+        host: host.to_string(),
+        keys: Keypair { secret, public },
+    })
+}
+
+/// Load a secret key from a file. Normally this is in the same `host:base64`
+/// form as `NIX_SIGNING_KEY`, but some tools write out the raw binary key
+/// instead; in that case the host is taken from the file name, so e.g.
+/// `cache.example.org-1.sec` is the key for `cache.example.org-1`.
+pub fn load_secret_key_file(path: &Path) -> Result<Keys> {
+    let bin = std::fs::read(path)?;
+    // anything that looks like a text key is one, so that a typo in it is
+    // reported rather than the text being loaded as a binary key; the same
+    // goes for text that's too short to be a binary key at all
+    match std::str::from_utf8(&bin) {
+        Ok(sk) if sk.contains(':') || bin.len() < 64 => return sk_to_keypair(sk.trim()),
+        _ if bin.len() < 64 => bail!("Invalid secret key length"),
+        _ => {}
+    }
+
+    let host = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(host) if !host.is_empty() => host,
+        _ => bail!("cannot get key name from file: {}", path.display()),
+    };
+    keypair_from_bytes(host, &bin)
+}
+
+/// Write a keypair out to `<base>.sec` and `<base>.pub`, the same way
+/// `nix-store --generate-binary-cache-key` does. The secret key is only
+/// readable by its owner. Both files are written next to their final names
+/// first and then renamed into place, so that a failure doesn't leave behind
+/// half of a keypair; existing files are only replaced if `force` is set.
+pub fn write_keys_file(base: &Path, keys: &Keys, force: bool) -> Result<()> {
+    let path = |ext: &str| {
+        let mut p = base.as_os_str().to_owned();
+        p.push(ext);
+        std::path::PathBuf::from(p)
+    };
+    let (sec, pub_) = (path(".sec"), path(".pub"));
+    if !force {
+        for p in [&sec, &pub_] {
+            if p.exists() {
+                bail!(
+                    "{} already exists; pass --force to overwrite it",
+                    p.display()
+                );
+            }
+        }
+    }
+
+    let (sec_tmp, pub_tmp) = (path(".sec.tmp"), path(".pub.tmp"));
+    // the mode only applies to new files, so don't reuse a stale one
+    let _ = std::fs::remove_file(&sec_tmp);
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    std::io::Write::write_all(&mut opts.open(&sec_tmp)?, keys.secret_key().as_bytes())?;
+    std::fs::write(&pub_tmp, keys.public_key())?;
+
+    std::fs::rename(&sec_tmp, &sec)?;
+    std::fs::rename(&pub_tmp, &pub_)?;
+    Ok(())
+}
+
+pub fn sk_to_pk(sk: &str) -> Result<String> {
+    let pieces: Vec<&str> = sk.split(":").collect();
+    if pieces.len() != 2 {
+        bail!("invalid sk: expected to contain hostname");
+    }
+
+    Ok(sk_to_keypair(sk)?.public_key())
+}
+
+/// The fields of a narinfo that its fingerprint, and so its signatures, are
+/// made from. Everything else (like the `URL`) can change freely.
+pub const SIGNED_FIELDS: [&str; 4] = ["StorePath", "NarHash", "NarSize", "References"];
+
+/// Split a `References` value into its entries, sorted and without any
+/// duplicates, which is how Nix itself keeps them.
+pub fn canonical_references(refs: &str) -> Vec<&str> {
+    let mut refs: Vec<&str> = refs.split_whitespace().collect();
+    refs.sort_unstable();
+    refs.dedup();
+    refs
+}
+
+/// Extract just the [`SIGNED_FIELDS`] of a narinfo, in that order, with the
+/// `References` in their canonical form. This shows why two narinfos that
+/// differ in other ways can still have the same signature. The fields are
+/// picked out the same way as for signing, so a duplicated one shows the
+/// value that actually got signed.
+pub fn signable_fields(body: &str) -> Result<String> {
+    let fields = signed_fields(body)?;
+    let mut refs = fields.refs.clone();
+    refs.sort_unstable();
+    refs.dedup();
+    Ok(format!(
+        "StorePath: {}\nNarHash: {}\nNarSize: {}\nReferences: {}\n",
+        fields.path,
+        fields.hash,
+        fields.size,
+        refs.join(" ")
+    ))
+}
+
+/// The fingerprint of a narinfo, i.e. the string which its signatures are
+/// made over: `1;<path>;<NarHash>;<NarSize>;<References>`, with the
+/// references as absolute paths, separated by commas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint(String);
+
+impl Fingerprint {
+    /// Check that a string has the structure of a fingerprint, e.g. one that
+    /// some other tool computed. Only the structure is checked, so it may
+    /// still be the fingerprint of a narinfo that doesn't exist.
+    pub fn new(fp: &str) -> Result<Fingerprint> {
+        let parts: Vec<&str> = fp.split(';').collect();
+        let (path, hash, size, refs) = match parts.as_slice() {
+            ["1", path, hash, size, refs] => (path, hash, size, refs),
+            _ => bail!(
+                "invalid fingerprint, expected 1;path;hash;size;refs: {}",
+                fp
+            ),
+        };
+        if !path.starts_with('/') {
+            bail!("invalid fingerprint, path isn't absolute: {}", path);
+        }
+        if !hash.contains(':') {
+            bail!("invalid fingerprint, hash has no type: {}", hash);
+        }
+        if size.parse::<u64>().is_err() {
+            bail!("invalid fingerprint, size is not a number: {}", size);
+        }
+        if let Some(r) = refs
+            .split(',')
+            .find(|r| !r.is_empty() && !r.starts_with('/'))
+        {
+            bail!("invalid fingerprint, reference isn't absolute: {}", r);
+        }
+        Ok(Fingerprint(fp.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Sign the fingerprint with a secret key.
+    pub fn sign(&self, ks: &Keys) -> Signature {
+        ks.keys.sign(self.0.as_bytes())
+    }
+}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The [`SIGNED_FIELDS`] of a narinfo, as they go into its fingerprint.
+struct SignedFields<'a> {
+    path: &'a str,
+    hash: &'a str,
+    size: u64,
+    refs: Vec<&'a str>,
+}
+
+/// Pull the [`SIGNED_FIELDS`] out of a narinfo. This is the one place that
+/// decides what a signature covers, so that signing, verifying and printing
+/// fingerprints all agree: every one of the fields is required, and when one
+/// occurs more than once, the last one wins.
+fn signed_fields(body: &str) -> Result<SignedFields<'_>> {
+    let mut ls = HashMap::new();
+    for x in body.lines() {
+        let x = x.trim();
+        if x.is_empty() {
+            continue;
+        }
+        let (k, v) = match split_once(x) {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+
+        if !SIGNED_FIELDS.contains(&k) {
+            continue;
+        }
+        ls.insert(k, v);
+    }
+
+    // this is fed narinfos straight from caches, so a missing field has to
+    // be an error rather than a panic
+    let field = |k: &str| required(k, ls.get(k).copied());
+    Ok(SignedFields {
+        path: field("StorePath")?,
+        hash: field("NarHash")?,
+        size: parse_number("NarSize", field("NarSize")?)?,
+        refs: field("References")?.split_whitespace().collect(),
+    })
+}
+
+impl SignedFields<'_> {
+    /// The references as absolute paths, in the order they're signed in.
+    fn absolute_refs(&self, store: &Store) -> Result<Vec<String>> {
+        let mut refs: Vec<String> = self
+            .refs
+            .iter()
+            .map(|x| store.absolute_path(x))
+            .collect::<Result<_>>()?;
+        // nix keeps references in a set, so they're always signed in sorted
+        // order, whatever order (or form) the narinfo lists them in
+        refs.sort_unstable();
+        refs.dedup();
+        Ok(refs)
+    }
+
+    fn fingerprint(&self, store: &Store) -> Result<Fingerprint> {
+        let refs = self.absolute_refs(store)?;
+        store.fingerprint_path(
+            self.path,
+            self.hash,
+            &self.size,
+            refs.iter().map(|x| x.as_str()),
+        )
+    }
+}
+
+/// Compute the fingerprint of a narinfo, i.e. the string which its signatures
+/// are made over.
+pub fn fingerprint_narinfo(store: &Store, body: &str) -> Result<Fingerprint> {
+    signed_fields(body)?.fingerprint(store)
+}
+
+/// Compute the fingerprint of a narinfo that's about to be signed, along with
+/// its `StorePath`. Unlike checking a signature, this fails if the
+/// `StorePath` isn't a valid store path, so that nothing malformed ever gets
+/// signed.
+fn fingerprint_for_signing<'a>(store: &Store, body: &'a str) -> Result<(&'a str, Fingerprint)> {
+    let fields = signed_fields(body)?;
+    store.check_store_path(fields.path)?;
+    Ok((fields.path, fields.fingerprint(store)?))
+}
+
+/// Describe every input to the fingerprint of a narinfo, along with the
+/// fingerprint itself, for comparing against what some other implementation
+/// computes.
+pub fn explain_fingerprint(store: &Store, body: &str) -> Result<String> {
+    let fields = signed_fields(body)?;
+    let fp = fields.fingerprint(store)?;
+
+    let mut out = format!("store dir: {}\nreferences:\n", store.store_path);
+    for r in fields.absolute_refs(store)? {
+        out.push_str(&format!("  {}\n", r));
+    }
+    out.push_str(&format!("fingerprint: {}\n", fp));
+    Ok(out)
+}
+
+/// Every field that Nix itself understands in a narinfo file.
+pub const KNOWN_FIELDS: [&str; 12] = [
+    "StorePath",
+    "URL",
+    "Compression",
+    "FileHash",
+    "FileSize",
+    "NarHash",
+    "NarSize",
+    "References",
+    "Deriver",
+    "System",
+    "Sig",
+    "CA",
+];
+
+/// Look up the canonical spelling of a known field, ignoring case, e.g.
+/// `narsize` is `NarSize`.
+pub fn canonical_field(name: &str) -> Option<&'static str> {
+    KNOWN_FIELDS
+        .iter()
+        .find(|k| k.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// Find every value of a field in a narinfo; only `Sig` should really occur
+/// more than once. The name is case insensitive for known fields, because
+/// nobody remembers whether it's `NarSize` or `NARSize`.
+pub fn query_field<'a>(body: &'a str, name: &str) -> Vec<&'a str> {
+    let name = canonical_field(name).unwrap_or(name);
+    body.lines()
+        .filter_map(|x| x.split_once(':'))
+        .filter(|(k, _)| k.trim() == name)
+        .map(|(_, v)| v.trim())
+        .collect()
+}
+
+/// The fields that a narinfo can't do without: the ones Nix needs to parse
+/// it, plus `References`, which is signed even when it's empty.
+pub const REQUIRED_FIELDS: [&str; 5] = ["StorePath", "URL", "NarHash", "NarSize", "References"];
+
+/// Check which of the [`KNOWN_FIELDS`] a narinfo has, in the order they're
+/// listed there.
+pub fn field_presence(body: &str) -> Vec<(&'static str, bool)> {
+    KNOWN_FIELDS
+        .iter()
+        .map(|&k| (k, !query_field(body, k).is_empty()))
+        .collect()
+}
+
+/// Check that a narinfo can be fingerprinted, and look for anything that's
+/// suspicious but not fatal, such as fields Nix doesn't know about, or a
+/// `Compression` that doesn't match the `URL`.
+pub fn validate_narinfo(store: &Store, body: &str, warnings: &mut Warnings) -> Result<()> {
+    fingerprint_narinfo(store, body)?;
+
+    for x in body.lines() {
+        match x.split_once(':') {
+            Some((k, _)) if !KNOWN_FIELDS.contains(&k.trim()) => {
+                warnings.warn(format!("unknown field: {}", k.trim()));
+            }
+            None if !x.trim().is_empty() => bail!(ParseError::MissingColon {
+                line: x.to_string()
+            }),
+            _ => {}
+        }
+    }
+
+    if let (Some(url), Some(compression)) =
+        (find_field(body, "URL"), find_field(body, "Compression"))
+    {
+        if let Some(ext) = compression_extension(compression) {
+            if !url.ends_with(ext) {
+                warnings.warn(format!(
+                    "URL {} doesn't match Compression {} (expected {})",
+                    url, compression, ext
+                ));
+            }
+        }
+    }
+
+    if let Some(deriver) = find_field(body, "Deriver") {
+        if deriver.starts_with('/') {
+            warnings.warn(format!(
+                "Deriver should be relative to the store: {}",
+                deriver
+            ));
+        }
+    }
+
+    if let Some(refs) = find_field(body, "References") {
+        let refs: Vec<&str> = refs.split_whitespace().collect();
+        if refs.windows(2).any(|w| w[0] > w[1]) {
+            warnings.warn("References are not sorted");
+        }
+    }
+
+    Ok(())
+}
+
+/// Warn if a narinfo's `References` don't include the path itself. A path
+/// that refers to itself has to list its own hash there, and a hand-edited
+/// narinfo which dropped it will fail `nix store verify`; but plenty of paths
+/// (e.g. plain data files) really don't refer to themselves, so this is only
+/// ever a warning, and only checked when asked for.
+pub fn check_self_reference(store: &Store, body: &str, warnings: &mut Warnings) -> Result<()> {
+    let path = match find_field(body, "StorePath") {
+        Some(path) => store.relative_path(path)?,
+        None => bail!("no StorePath found"),
+    };
+    let hash = path.split('-').next().unwrap_or(path);
+
+    let refs = find_field(body, "References").unwrap_or("");
+    if !refs
+        .split_whitespace()
+        .any(|r| r.split('-').next() == Some(hash))
+    {
+        warnings.warn(format!(
+            "References don't include the path itself: {}",
+            path
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a content-addressed narinfo's `StorePath` is really the path
+/// that its `CA` field implies, so that nobody can pass off some other
+/// contents under the name of a known path. For `fixed:r:sha256` paths, the
+/// `CA` hash is the `NarHash` itself, so that has to match too.
+///
+/// Input-addressed paths (without a `CA` field) are hashed from their
+/// derivation instead, which isn't in the narinfo, so they can't be checked.
+pub fn check_ca_store_path(store: &Store, body: &str) -> Result<bool> {
+    let info: NarInfo = body.parse()?;
+    let ca = match info.extra.get("CA") {
+        Some(ca) => ca,
+        None => return Ok(false),
+    };
+
+    let base = store.relative_path(&info.store_path)?;
+    let (hash, name) = match base.split_once('-') {
+        Some(x) => x,
+        None => bail!("invalid store path: {}", info.store_path),
+    };
+    let refs = info.references_absolute(store)?;
+    let self_ref = refs.contains(&info.store_path);
+    let others: Vec<&str> = refs
+        .iter()
+        .map(|r| r.as_str())
+        .filter(|r| *r != info.store_path)
+        .collect();
+
+    if let Some(ca_hash) = ca.strip_prefix("fixed:r:sha256:") {
+        let nar_hash = base32_hash(&normalize_hash_algo(&info.nar_hash))?;
+        let ca_hash = base32_hash(&format!("sha256:{}", ca_hash))?;
+        if nar_hash != ca_hash {
+            bail!("NarHash {} doesn't match CA {}", info.nar_hash, ca);
+        }
+    }
+
+    let expected = store.ca_store_path_hash(ca, name, &others, self_ref)?;
+    if expected != hash {
+        bail!(
+            "StorePath {} doesn't match CA {} (expected hash {})",
+            info.store_path,
+            ca,
+            expected
+        );
+    }
+    Ok(true)
+}
+
+/// Sign a narinfo, returning the `host:base64` value of its new `Sig` field.
+/// This needs exactly the same fields as verifying it does, and fails if the
+/// `StorePath` isn't a valid store path.
+pub fn sign_narinfo(store: &Store, ks: &Keys, body: &str) -> Result<String> {
+    let (_, fp) = fingerprint_for_signing(store, body)?;
+    Ok(sign_fingerprint(ks, &fp))
+}
+
+/// Sign a narinfo like [`sign_narinfo`], but alongside the signatures it
+/// already has, returning all of them as `host:base64`, in order, with the new
+/// one last. If there's already a signature from the same host, it's kept and
+/// nothing new is signed, so doing this twice doesn't pile up duplicates. The
+/// kept signatures are written the same way as new ones, with their padding
+/// restored and any stray whitespace gone.
+pub fn sign_narinfo_keeping_sigs(store: &Store, ks: &Keys, body: &str) -> Result<Vec<String>> {
+    sign_narinfo_with_keys(store, std::slice::from_ref(ks), body)
+}
+
+/// Sign a narinfo like [`sign_narinfo_keeping_sigs`], but with each of several
+/// keys in turn, e.g. both the old and new keys while rotating them. The new
+/// signatures come after the existing ones, in the same order as the keys,
+/// and a key is skipped if its host has already signed.
+pub fn sign_narinfo_with_keys(store: &Store, keys: &[Keys], body: &str) -> Result<Vec<String>> {
+    let (_, fp) = fingerprint_for_signing(store, body)?;
+    let mut hosts: Vec<&str> = Vec::new();
+    let mut sigs: Vec<String> = Vec::new();
+    for v in query_field(body, "Sig") {
+        match parse_sig(v) {
+            Ok((host, sig)) => {
+                hosts.push(host);
+                sigs.push(format_sig(host, &tidy_base64(sig)));
+            }
+            // it isn't ours to fix, so keep it as it is
+            Err(_) => sigs.push(v.to_string()),
+        }
+    }
+    for ks in keys {
+        if !hosts.contains(&ks.host.as_str()) {
+            sigs.push(sign_fingerprint(ks, &fp));
+            hosts.push(&ks.host);
+        }
+    }
+    Ok(sigs)
+}
+
+/// Replace the `Sig` lines of a narinfo with the given signatures, which
+/// come at the end, in order.
+pub fn replace_signatures(body: &str, sigs: &[String]) -> String {
+    let mut out: String = body
+        .lines()
+        .filter(|l| !matches!(l.split_once(':'), Some((k, _)) if k.trim() == "Sig"))
+        .map(|l| format!("{}\n", l.trim_end()))
+        .collect();
+    for sig in sigs {
+        out.push_str(&format!("Sig: {}\n", sig));
+    }
+    out
+}
+
+/// Add a `Sig` line to the end of a narinfo. Nix only ever writes narinfos
+/// with LF line endings, so any CRLF ones from the input are converted rather
+/// than ending up mixed with the new line.
+pub fn append_signature(body: &str, sig: &str) -> String {
+    format!("{}\nSig: {}", body.trim_end().replace("\r\n", "\n"), sig)
+}
+
+/// Sign a fingerprint, producing the `host:base64` value of a `Sig` field.
+fn sign_fingerprint(ks: &Keys, fp: &Fingerprint) -> String {
+    format_sig(&ks.host, &base64::encode(fp.sign(ks).to_bytes()))
+}
+
+/// Write a signature as the `host:base64` value of a `Sig` field.
+fn format_sig(host: &str, sig: &str) -> String {
+    format!("{}:{}", host, sig)
+}
+
+/// Sign a narinfo `body` that was read from the file at `path`, and replace
+/// that file with the signed version. The new file is written next to the old
+/// one and then renamed over it, so a reader never sees half a narinfo. It's
+/// given a fresh name each time, so that two signers working on the same file
+/// can't write into each other's copies, and it keeps the old file's mode.
+///
+/// Returns `false` without touching the file if it already has this exact
+/// signature; ed25519 signatures are deterministic, so that means it's
+/// already been signed by this key.
+pub fn sign_in_place(path: &Path, body: &str, store: &Store, ks: &Keys) -> Result<bool> {
+    let sig = sign_narinfo(store, ks, body)?;
+    if query_field(body, "Sig").contains(&sig.as_str()) {
+        return Ok(false);
+    }
+
+    let (tmp, f) = create_temp_file(path)?;
+    let contents = format!("{}\n", append_signature(body, &sig));
+    // whatever goes wrong, don't leave the copy lying around
+    if let Err(e) = write_replacement(f, &contents, path).and_then(|_| std::fs::rename(&tmp, path))
+    {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(true)
+}
+
+/// Write the new contents of `original` into the temporary file `f`, giving it
+/// the same mode as `original`. The file is closed again before returning, so
+/// it's ready to be renamed into place.
+fn write_replacement(mut f: std::fs::File, contents: &str, original: &Path) -> std::io::Result<()> {
+    std::io::Write::write_all(&mut f, contents.as_bytes())?;
+    // only unix has a mode worth keeping, and WASI can't set one at all
+    #[cfg(unix)]
+    f.set_permissions(std::fs::metadata(original)?.permissions())?;
+    #[cfg(not(unix))]
+    let _ = original;
+    Ok(())
+}
+
+/// Create a new, empty file next to `path`, with a random name that nothing
+/// else is using, for writing a replacement for `path` into.
+fn create_temp_file(path: &Path) -> Result<(std::path::PathBuf, std::fs::File)> {
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => bail!("not a file: {}", path.display()),
+    };
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    // a name can only clash with a leftover from a crashed run (or a very
+    // unlucky draw), so a few tries are plenty
+    for _ in 0..16 {
+        let mut tmp = std::ffi::OsString::from(".");
+        tmp.push(name);
+        tmp.push(format!(".{:016x}.tmp", rand::RngCore::next_u64(&mut OsRng)));
+        let tmp = path.with_file_name(tmp);
+        match opts.open(&tmp) {
+            Ok(f) => return Ok((tmp, f)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    bail!(
+        "couldn't create a temporary file next to {}",
+        path.display()
+    )
+}
+
+/// Sign a narinfo like [`sign_narinfo`], but describe the result as a JSON
+/// object with the `storePath`, the new `sig`, and the `fingerprint` that was
+/// signed, for tools that want to handle the signature themselves.
+pub fn sign_narinfo_to_json(store: &Store, ks: &Keys, body: &str) -> Result<String> {
+    let (path, fp) = fingerprint_for_signing(store, body)?;
+    let result = serde_json::json!({
+        "storePath": path,
+        "sig": sign_fingerprint(ks, &fp),
+        "fingerprint": fp.as_str(),
+    });
+    Ok(result.to_string())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Parse a public key in the `host:base64` form produced by [`sk_to_pk`].
+pub fn parse_public_key(pk: &str) -> Result<(&str, PublicKey)> {
+    let (host, dat) = match pk.split_once(':') {
+        Some(x) => x,
+        None => bail!("invalid pk: expected to contain hostname"),
+    };
+
+    let bin = decode_base64(dat)?;
+    if bin.len() != 32 {
+        bail!("Invalid public key length");
+    }
+    Ok((host, PublicKey::from_bytes(&bin)?))
+}
+
+/// Check whether two `host:base64` public keys are the same key, regardless of
+/// their host names, e.g. to catch a key that's been trusted under two names.
+pub fn same_public_key(a: &str, b: &str) -> Result<bool> {
+    let (_, a) = parse_public_key(a)?;
+    let (_, b) = parse_public_key(b)?;
+    Ok(a == b)
+}
+
+/// A set of trusted public keys, indexed by host.
+///
+/// Decoding a public key means base64 decoding it and decompressing a curve
+/// point, which takes around 5us, versus around 70us to verify a narinfo (on
+/// a release build, x86_64). So when checking many narinfos, which are mostly
+/// signed by the same one or two keys, parsing the keys once into a
+/// `TrustStore` and sharing it saves roughly 7% per signature, plus the
+/// allocations.
+#[derive(Debug, Default, Clone)]
+pub struct TrustStore {
+    keys: HashMap<String, Vec<PublicKey>>,
+    /// Keys which have been rotated out, but whose old signatures are still
+    /// accepted (with a warning).
+    retired: HashMap<String, Vec<PublicKey>>,
+}
+
+impl TrustStore {
+    pub fn new() -> TrustStore {
+        TrustStore::default()
+    }
+
+    /// Parse a whitespace-separated list of `host:base64` public keys, like
+    /// Nix's `trusted-public-keys` setting.
+    pub fn parse(pks: &str) -> Result<TrustStore> {
+        let mut ts = TrustStore::new();
+        for pk in pks.split_whitespace() {
+            ts.add(pk)?;
+        }
+        Ok(ts)
+    }
+
+    /// Also trust any keys listed in `# trusted-keys: ...` comment lines in a
+    /// narinfo, so that a single file can carry everything needed to verify
+    /// it, e.g. in a test bundle.
+    pub fn add_embedded(&mut self, body: &str) -> Result<()> {
+        for x in body.lines() {
+            if let Some(pks) = x.trim().strip_prefix("# trusted-keys:") {
+                for pk in pks.split_whitespace() {
+                    self.add(pk)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Trust another `host:base64` public key.
+    pub fn add(&mut self, pk: &str) -> Result<()> {
+        let (host, pk) = parse_public_key(pk)?;
+        self.keys.entry(host.to_string()).or_default().push(pk);
+        Ok(())
+    }
+
+    /// Accept signatures from a retired `host:base64` public key, i.e. one
+    /// that was rotated out but which old narinfos are still signed with.
+    pub fn add_retired(&mut self, pk: &str) -> Result<()> {
+        let (host, pk) = parse_public_key(pk)?;
+        self.retired.entry(host.to_string()).or_default().push(pk);
+        Ok(())
+    }
+
+    /// All the trusted keys for a host, of which there may be several.
+    pub fn keys_for(&self, host: &str) -> &[PublicKey] {
+        self.keys.get(host).map(|ks| ks.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.retired.is_empty()
+    }
+}
+
+/// The result of checking a single `Sig` of a narinfo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigStatus {
+    /// The signature was made by one of the trusted keys.
+    Valid,
+    /// There's a trusted key for the host, but the signature doesn't match.
+    Invalid,
+    /// There's no trusted key for the host at all.
+    Untrusted,
+    /// The signature was made by a retired key, which is still accepted.
+    Retired,
+}
+
+impl SigStatus {
+    /// Whether the signature should be accepted, possibly from a retired key.
+    pub fn is_valid(&self) -> bool {
+        matches!(self, SigStatus::Valid | SigStatus::Retired)
+    }
+}
+
+impl std::fmt::Display for SigStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SigStatus::Valid => "valid",
+            SigStatus::Invalid => "invalid signature",
+            SigStatus::Untrusted => "untrusted key",
+            SigStatus::Retired => "valid (retired key)",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigCheck {
+    pub host: String,
+    pub status: SigStatus,
+    /// The trusted key which the signature verified against, if it's valid.
+    /// Several keys can share a host label, so this says exactly which one.
+    pub key: Option<PublicKey>,
+}
+
+impl SigCheck {
+    /// The base64 encoding of the matching key, if any.
+    pub fn key_base64(&self) -> Option<String> {
+        self.key.map(|pk| base64::encode(pk.to_bytes()))
+    }
+}
+
+/// Check every signature in a narinfo against a set of trusted public keys,
+/// returning the status of each one, in the order they appear.
+pub fn verify_narinfo(
+    store: &Store,
+    trusted: &TrustStore,
+    body: &str,
+    warnings: &mut Warnings,
+) -> Result<Vec<SigCheck>> {
+    let fp = fingerprint_narinfo(store, body)?;
+
+    let mut checks = Vec::new();
+    for x in body.lines() {
+        let (k, v) = match x.split_once(':') {
+            Some((k, v)) if k.trim() == "Sig" => (k, v),
+            _ => continue,
+        };
+        let (host, sig) = match parse_sig(v) {
+            Ok(x) => x,
+            Err(e) => {
+                warnings.warn(format!("ignoring malformed {}: {}", k.trim(), e));
+                continue;
+            }
+        };
+
+        let check = check_sig(trusted, &fp, host, sig);
+        if check.status == SigStatus::Retired {
+            warnings.warn(format!("{}: signed by retired key", host));
+        }
+        checks.push(check);
+    }
+    Ok(checks)
+}
+
+/// Check a single signature from `host` over a fingerprint.
+fn check_sig(trusted: &TrustStore, fp: &Fingerprint, host: &str, sig: &str) -> SigCheck {
+    let keys = trusted.keys_for(host);
+    let retired = trusted
+        .retired
+        .get(host)
+        .map(|ks| ks.as_slice())
+        .unwrap_or(&[]);
+    let find = |keys: &[PublicKey]| match base64::decode(tidy_base64(sig))
+        .map(|b| Signature::from_bytes(&b))
+    {
+        Ok(Ok(sig)) => keys
+            .iter()
+            .find(|pk| pk.verify(fp.as_str().as_bytes(), &sig).is_ok())
+            .copied(),
+        _ => None,
+    };
+
+    let (status, key) = match (find(keys), find(retired)) {
+        (Some(key), _) => (SigStatus::Valid, Some(key)),
+        (None, Some(key)) => (SigStatus::Retired, Some(key)),
+        _ if keys.is_empty() && retired.is_empty() => (SigStatus::Untrusted, None),
+        _ => (SigStatus::Invalid, None),
+    };
+
+    SigCheck {
+        host: host.to_string(),
+        status,
+        key,
+    }
+}
+
+/// Restore any `=` padding stripped from the end of some base64, which some
+/// tools do to signatures in the wild, and drop any whitespace inside it, as
+/// left behind when a signature gets line-wrapped.
+fn tidy_base64(s: &str) -> String {
+    let s = unwrap_base64(s);
+    let missing = (4 - s.len() % 4) % 4;
+    format!("{}{}", s, "=".repeat(missing))
+}
+
+/// Join line-wrapped base64 back into a single line.
+fn unwrap_base64(s: &str) -> String {
+    s.split_whitespace().collect()
+}
+
+/// Decode a key's base64, ignoring any whitespace inside it.
+fn decode_base64(s: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode(unwrap_base64(s))?)
+}
+
+/// Count the number of valid and not-valid signatures in a set of checks.
+pub fn tally(checks: &[SigCheck]) -> (usize, usize) {
+    let valid = checks.iter().filter(|c| c.status.is_valid()).count();
+    (valid, checks.len() - valid)
+}
+
+/// Decide whether a narinfo verified, i.e. whether any of its signatures are
+/// valid. A narinfo without any signatures at all is an error rather than a
+/// plain failure, unless `allow_unsigned` is set, since there was nothing to
+/// check in the first place.
+pub fn verified(checks: &[SigCheck], allow_unsigned: bool) -> Result<bool> {
+    if checks.is_empty() {
+        if allow_unsigned {
+            return Ok(true);
+        }
+        bail!("no signatures present");
+    }
+    Ok(tally(checks).0 > 0)
+}
+
+/// Hash a NAR the same way as its `NarHash`, returning `sha256:<base32>` and
+/// its size. The NAR is streamed through in `chunk_size` pieces, so even huge
+/// ones don't need to fit in memory.
+pub fn hash_nar<R: std::io::Read>(mut nar: R, chunk_size: usize) -> Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; chunk_size];
+    let mut size = 0;
+    loop {
+        match nar.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buf[..n]);
+                size += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let hash = format!("sha256:{}", base32::encode(&hasher.finalize()));
+    Ok((hash, size))
+}
+
+/// Check a NAR against the `NarHash` and `NarSize` of a narinfo, returning a
+/// description of each one that doesn't match; no descriptions means that the
+/// NAR is exactly the one the narinfo describes.
+pub fn check_nar<R: std::io::Read>(body: &str, nar: R, chunk_size: usize) -> Result<Vec<String>> {
+    let info: NarInfo = body.parse()?;
+    let expected = base32_hash(&normalize_hash_algo(&info.nar_hash))?;
+    let (hash, size) = hash_nar(nar, chunk_size)?;
+
+    let mut mismatches = Vec::new();
+    if hash != expected {
+        mismatches.push(format!(
+            "NarHash mismatch: narinfo has {}, but the NAR is {}",
+            info.nar_hash, hash
+        ));
+    }
+    if size != info.nar_size {
+        mismatches.push(format!(
+            "NarSize mismatch: narinfo has {}, but the NAR is {}",
+            info.nar_size, size
+        ));
+    }
+    Ok(mismatches)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone)]
+pub struct Store {
+    store_path: String,
+}
+
+impl Store {
+    /// A store in the given directory. A trailing `/` is dropped, since
+    /// `NIX_STORE_DIR=/nix/store/` would otherwise put a `//` in every
+    /// absolute reference, and change the fingerprint.
+    pub fn new(store_path: &str) -> Result<Store> {
+        let trimmed = store_path.trim_end_matches('/');
+        if trimmed.is_empty() && !store_path.is_empty() {
+            bail!("the store dir can't be the root directory");
+        }
+        Ok(Store {
+            store_path: trimmed.to_string(),
+        })
+    }
+
+    /// Infer the store directory from a full store path, by taking the
+    /// directory that contains the `<hash>-<name>` component; e.g.
+    /// `/nix/store/...-yosys-0.15` is in the store `/nix/store`.
+    pub fn from_store_path(path: &str) -> Result<Store> {
+        match path.rsplit_once('/') {
+            Some((dir, name)) if !dir.is_empty() && !name.is_empty() => Store::new(dir),
+            _ => bail!("cannot infer store dir from path: {}", path),
+        }
+    }
+
+    /// Check whether a path lives inside of this store.
+    pub fn contains(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.store_path) {
+            Some(rest) => rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    /// Make an absolute path inside of this store relative to it, i.e. just
+    /// the `<hash>-<name>` part, as used for `References` and `Deriver`.
+    pub fn relative_path<'a>(&self, path: &'a str) -> Result<&'a str> {
+        if !self.contains(path) {
+            bail!("{} is not in the store {}", path, self.store_path);
+        }
+        Ok(&path[self.store_path.len() + 1..])
+    }
+
+    /// Check that a path is a store path in this store, i.e. that the part
+    /// after the store dir is a 32 character base32 hash, a dash, and a name.
+    pub fn check_store_path(&self, path: &str) -> Result<()> {
+        let valid = match self.relative_path(path)?.split_once('-') {
+            Some((hash, name)) => {
+                hash.len() == 32
+                    && base32::decode(hash).is_ok()
+                    && !name.is_empty()
+                    && !name.contains('/')
+            }
+            None => false,
+        };
+        if !valid {
+            bail!(
+                "invalid store path, expected {}/<32 character hash>-<name>: {}",
+                self.store_path,
+                path
+            );
+        }
+        Ok(())
+    }
+
+    /// The opposite of [`relative_path`](Self::relative_path): put a relative
+    /// `<hash>-<name>` path inside of this store. A path that's already
+    /// absolute is left alone, but it must be inside of this store, since a
+    /// narinfo can't refer to paths in some other store.
+    pub fn absolute_path(&self, path: &str) -> Result<String> {
+        if path.starts_with('/') {
+            self.relative_path(path)?;
+            Ok(path.to_string())
+        } else {
+            Ok(format!("{}/{}", self.store_path, path))
+        }
+    }
+
+    /// Compute the hash part of a store path from its inputs, exactly like
+    /// Nix does: `typ` is the kind of path along with any references, e.g.
+    /// `text:/nix/store/...-foo` or `source`, and `hash` is the inner hash in
+    /// the form `sha256:<base16>`.
+    ///
+    /// The result is the sha256 of `<typ>:<hash>:<store dir>:<name>`,
+    /// XOR-folded down to 160 bits and encoded in Nix base32.
+    pub fn store_path_hash(&self, typ: &str, hash: &str, name: &str) -> Result<String> {
+        match hash.strip_prefix("sha256:") {
+            Some(hex) if hex.len() == 64 && hex.bytes().all(|c| c.is_ascii_hexdigit()) => {}
+            _ => bail!("hash must be a base16 sha256 hash"),
+        }
+
+        let s = format!("{}:{}:{}:{}", typ, hash, self.store_path, name);
+        let digest = Sha256::digest(s.as_bytes());
+
+        let mut folded = [0u8; 20];
+        for (i, b) in digest.iter().enumerate() {
+            folded[i % 20] ^= b;
+        }
+        Ok(base32::encode(&folded))
+    }
+
+    /// Compute the hash part of a content-addressed store path from its `CA`
+    /// field, e.g. `text:sha256:<base32>` or `fixed:r:sha256:<base32>`, the
+    /// same way Nix does when first adding the path. `refs` are the absolute
+    /// paths it refers to, other than itself; `self_ref` is whether it also
+    /// refers to itself, which only a `fixed:r:sha256` path can do.
+    pub fn ca_store_path_hash(
+        &self,
+        ca: &str,
+        name: &str,
+        refs: &[&str],
+        self_ref: bool,
+    ) -> Result<String> {
+        let mut refs = refs.to_vec();
+        refs.sort_unstable();
+        let with_refs = |typ: &str| -> String {
+            refs.iter()
+                .fold(typ.to_string(), |t, r| format!("{}:{}", t, r))
+        };
+
+        let (method, rest) = match ca.split_once(':') {
+            Some((method, rest)) => (method, rest),
+            None => bail!("invalid CA field: {}", ca),
+        };
+        let (recursive, rest) = match rest.strip_prefix("r:") {
+            Some(rest) if method == "fixed" => (true, rest),
+            _ => (false, rest),
+        };
+        let (algo, hash) = match rest.split_once(':') {
+            Some((algo, hash)) => (algo, ca_hash_to_base16(algo, hash)?),
+            None => bail!("invalid CA field: {}", ca),
+        };
+        if self_ref && !(method == "fixed" && recursive && algo == "sha256") {
+            bail!("only fixed:r:sha256 paths can refer to themselves: {}", ca);
+        }
+
+        match (method, recursive, algo) {
+            ("text", _, "sha256") => {
+                self.store_path_hash(&with_refs("text"), &format!("sha256:{}", hash), name)
+            }
+            ("fixed", true, "sha256") => {
+                let mut typ = with_refs("source");
+                if self_ref {
+                    typ.push_str(":self");
+                }
+                self.store_path_hash(&typ, &format!("sha256:{}", hash), name)
+            }
+            ("fixed", _, _) => {
+                if !refs.is_empty() {
+                    bail!("fixed output paths can't have references: {}", ca);
+                }
+                let inner = format!(
+                    "fixed:out:{}{}:{}:",
+                    if recursive { "r:" } else { "" },
+                    algo,
+                    hash
+                );
+                let inner: String = Sha256::digest(inner.as_bytes())
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                self.store_path_hash("output:out", &format!("sha256:{}", inner), name)
+            }
+            _ => bail!("unsupported CA field: {}", ca),
+        }
+    }
+
+    pub fn fingerprint_path<'a, I>(
+        &self,
+        path: &str,
+        hash: &str,
+        size: &u64,
+        refs: I,
+    ) -> anyhow::Result<Fingerprint>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        // https://github.com/NixOS/nix/blob/2.10.3/perl/lib/Nix/Manifest.pm#L234
+
+        // a plain prefix check would let NIX_STORE_DIR=/nix/sto match paths
+        // in /nix/store, so require the store dir to end at a '/'
+        if !self.contains(path) {
+            bail!("path must be inside of store dir {}", self.store_path);
+        }
+
+        // only the fingerprint gets the base32 form; the narinfo itself is
+        // left however it was written
+        let hash = base32_hash(&normalize_hash_algo(hash))?;
+
+        // Nix itself writes sha256, but older or custom caches may use sha1
+        // or sha512 instead
+        let algo = hash.split_once(':').map_or(hash.as_str(), |(algo, _)| algo);
+        let bytes = match hash_size(algo) {
+            Some(bytes) if FINGERPRINT_HASH_ALGOS.contains(&algo) => bytes,
+            _ => bail!(ParseError::BadHashAlgo {
+                algo: algo.to_string()
+            }),
+        };
+
+        let expected = algo.len() + 1 + base32::encoded_len(bytes);
+        if hash.len() != expected {
+            bail!(ParseError::BadHashLength {
+                expected,
+                got: hash.len()
+            });
+        }
+        base32::decode(&hash[algo.len() + 1..])
+            .with_context(|| format!("invalid NarHash {}", hash))?;
+
+        let refs = refs
+            .into_iter()
+            .map(|p| {
+                if !self.contains(p) {
+                    bail!("ref must be inside of store dir {}", self.store_path);
+                } else {
+                    Ok(p)
+                }
+            })
+            .collect::<Result<Vec<&'a str>>>()?
+            .join(",");
+
+        Ok(Fingerprint(format!(
+            "1;{};{};{};{}",
+            path, hash, size, refs
+        )))
+    }
+}
+
+/// The hash algorithms that a `NarHash` may use in a fingerprint.
+const FINGERPRINT_HASH_ALGOS: [&str; 3] = ["sha1", "sha256", "sha512"];
+
+/// A set of several stores, for working with narinfos which may come from any
+/// one of them, e.g. both `/nix/store` and a chroot store.
+#[derive(Debug, Clone)]
+pub struct MultiStore {
+    stores: Vec<Store>,
+}
+
+impl MultiStore {
+    pub fn new(store_paths: &[&str]) -> Result<MultiStore> {
+        let stores = store_paths
+            .iter()
+            .map(|p| Store::new(p))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(MultiStore { stores })
+    }
+
+    /// Parse a `:`-separated list of store directories, in the style of
+    /// `$PATH`.
+    pub fn parse(store_paths: &str) -> Result<MultiStore> {
+        let paths: Vec<&str> = store_paths.split(':').filter(|p| !p.is_empty()).collect();
+        if paths.is_empty() {
+            bail!("no store directories given");
+        }
+        MultiStore::new(&paths)
+    }
+
+    /// Find the store that a path lives in. If several of them match (i.e.
+    /// one is nested inside another) then the longest one wins.
+    pub fn store_for(&self, path: &str) -> Option<&Store> {
+        self.stores
+            .iter()
+            .filter(|s| s.contains(path))
+            .max_by_key(|s| s.store_path.len())
+    }
+
+    /// Validate a narinfo like [`validate_narinfo`], using whichever of the
+    /// stores its `StorePath` lives in.
+    pub fn validate(&self, body: &str, warnings: &mut Warnings) -> Result<()> {
+        let path = match find_field(body, "StorePath") {
+            Some(path) => path,
+            None => bail!("no StorePath found"),
+        };
+        let store = match self.store_for(path) {
+            Some(store) => store,
+            None => bail!("{} is not in any known store", path),
+        };
+        validate_narinfo(store, body, warnings)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::sk_to_keypair;
+    use crate::error::ParseError;
+    use crate::narinfo::{
+        append_signature, canonical_field, check_ca_store_path, check_max_line_length,
+        check_max_references, check_nar, check_required_fields, check_self_reference,
+        check_strict_colons, diff_narinfos, explain_fingerprint, field_presence, find_field,
+        fingerprint_narinfo, hash_nar, json_to_narinfo, json_to_narinfo_with, keypair_from_seed,
+        load_secret_key_file, narinfo_to_canonical_json, narinfo_to_json, narinfo_to_json_with,
+        narinfo_to_json_writer, narinfos_to_ndjson, normalize_hash_algo, normalize_hashes,
+        parse_public_key, parse_seed, parse_sig, path_info_to_narinfos, query_field, read_chunked,
+        read_chunked_limited, replace_signatures, same_public_key, sign_in_place, sign_narinfo,
+        sign_narinfo_keeping_sigs, sign_narinfo_to_json, sign_narinfo_with_keys, signable_fields,
+        sk_to_pk, tally, validate_narinfo, verified, verify_narinfo, write_keys_file, FieldDiff,
+        Fingerprint, JsonBuffer, JsonOptions, Keys, MultiStore, NarInfo, NarInfoBuilder, SigStatus,
+        Store, TrustStore, Warnings, BARE_SIG_HOST, DEFAULT_CHUNK_SIZE, DEFAULT_MAX_REFERENCES,
+        KNOWN_FIELDS, SIGNED_FIELDS,
+    };
+
+    const YOSYS: &str = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
+Compression: xz
+FileHash: sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds
+FileSize: 3542408
+NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
+NarSize: 17680416
+References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib 20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2 9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11 ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11 dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16 dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15 fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3 hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10 klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04 q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117 sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08
+Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv
+Sig: cache.nixos.org-1:eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA=="#;
+
+    const CACHE_NIXOS_ORG_PK: &str =
+        "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
+
+    #[test]
+    fn test_narinfo_to_json() {
+        let input = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
+Compression: xz
+FileHash: sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds
+FileSize: 3542408
+NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
+NarSize: 17680416
+References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib 20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2 9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11 ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11 dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16 dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15 fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3 hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10 klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04 q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117 sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08
+Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv
+Sig: cache.nixos.org-1:eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA=="#;
+        let expected = r#"{ "StorePath": "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15", "URL": "nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz", "Compression": "xz", "FileHash": { "type": "sha256", "hash": "06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds" }, "FileSize": 3542408, "NarHash": { "type": "sha256", "hash": "1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3" }, "NarSize": 17680416, "References": [ "18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib", "20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2", "9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11", "ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11", "dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16", "dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15", "fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3", "hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10", "klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04", "q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117", "sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev", "yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08" ], "Deriver": "x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv", "Sig": { "cache.nixos.org-1": "eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA==" } }"#;
+
+        let mut output = String::new();
+        crate::narinfo::narinfo_to_json(input.to_string(), &mut output);
+        assert_eq!(expected.trim(), output.trim());
+    }
+
+    #[test]
+    fn test_narinfo_to_json_empty_sig() {
+        let input = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+Sig: cache.nixos.org-1:"#;
+        let expected =
+            r#"{ "StorePath": "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15" }"#;
+
+        assert!(parse_sig("cache.nixos.org-1:").is_err());
+        assert!(parse_sig(":eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA==").is_err());
+
+        let mut output = String::new();
+        crate::narinfo::narinfo_to_json(input.to_string(), &mut output);
+        assert_eq!(expected.trim(), output.trim());
+
+        // the warning is only collected by the _with variant
+        let mut output = String::new();
+        let mut warnings = Warnings::new();
+        narinfo_to_json_with(
+            input.to_string(),
+            &mut output,
+            &JsonOptions::default(),
+            &mut warnings,
+        )
+        .unwrap();
+        assert_eq!(expected.trim(), output.trim());
+        assert_eq!(warnings.messages().len(), 1);
+    }
+
+    #[test]
+    fn test_narinfo_to_json_matches_writer() {
+        // the same as the json mode, even for fields Nix doesn't know about
+        // that come before the Sig
+        let input = YOSYS.replace("\nSig:", "\nSystem: x86_64-linux\nSig:");
+        let mut output = String::new();
+        narinfo_to_json(input.clone(), &mut output);
+
+        let mut written = Vec::new();
+        narinfo_to_json_writer(
+            &input,
+            &mut written,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        assert_eq!(output, String::from_utf8(written).unwrap());
+        assert!(output.find("\"System\"").unwrap() < output.find("\"Sig\"").unwrap());
+    }
+
+    #[test]
+    fn test_narinfo_builder() {
+        let expected = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
+Compression: xz
+FileHash: sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds
+FileSize: 3542408
+NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
+NarSize: 17680416
+References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv
+Sig: cache.nixos.org-1:eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA==
+"#;
+
+        let narinfo = NarInfoBuilder::new()
+            .store_path("/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15")
+            .url("nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz")
+            .compression("xz")
+            .file_hash("sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds")
+            .file_size(3542408)
+            .nar_hash("sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3")
+            .nar_size(17680416)
+            .reference("18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib")
+            .reference("dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15")
+            .deriver("x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv")
+            .sig("cache.nixos.org-1", "eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA==")
+            .build()
+            .unwrap();
+        assert_eq!(expected, narinfo.to_string());
+
+        let missing = NarInfoBuilder::new()
+            .store_path("/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15")
+            .url("nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz")
+            .nar_size(17680416)
+            .build();
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            "missing required field: NarHash"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_path() {
+        let path = "/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1";
+        let hash = "sha256:0si0g30ksvlz953ysczn7jb0z942xzhrzwzx6h94f76r9k8269ph";
+        let size = 64184;
+        let refs = vec![
+            "/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1",
+            "/nix/store/d2bpliayddadf6lx6l1i04w265gqw8n6-glibc-2.34-210",
+        ];
+
+        let expected = "1;/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1;sha256:0si0g30ksvlz953ysczn7jb0z942xzhrzwzx6h94f76r9k8269ph;64184;/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1,/nix/store/d2bpliayddadf6lx6l1i04w265gqw8n6-glibc-2.34-210";
+
+        let s = Store::new("/nix/store").unwrap();
+        assert_eq!(
+            s.fingerprint_path(path, hash, &size, refs)
+                .unwrap()
+                .as_str(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_path_base16() {
+        // the NarHash of yosys-0.15 on cache.nixos.org, in both encodings
+        let base16 = "sha256:a342d864795cee832afd439af506723f95819a61162b94cabdb2103d2afefed6";
+        let base32 = "sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3";
+        let path = "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15";
+
+        let s = Store::new("/nix/store").unwrap();
+        let fp = s.fingerprint_path(path, base16, &17680416, vec![]).unwrap();
+        assert_eq!(
+            fp,
+            s.fingerprint_path(path, base32, &17680416, vec![]).unwrap()
+        );
+        assert!(fp.as_str().contains(base32));
+
+        // and cache.nixos.org's own signature still checks out
+        let body = YOSYS.replace(base32, base16);
+        assert_ne!(body, YOSYS);
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+        let checks = verify_narinfo(&s, &trusted, &body, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Valid);
+    }
+
+    #[test]
+    fn test_sri_hashes() {
+        // the NarHash of yosys-0.15 again, this time in SRI form
+        let sri = "sha256-o0LYZHlc7oMq/UOa9QZyP5WBmmEWK5TKvbIQPSr+/tY=";
+        let base32 = "1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3";
+        let body = YOSYS.replace(
+            &format!("NarHash: sha256:{}", base32),
+            &format!("NarHash: {}", sri),
+        );
+        assert_ne!(body, YOSYS);
+
+        let mut output = String::new();
+        narinfo_to_json(body.clone(), &mut output);
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["NarHash"],
+            serde_json::json!({ "type": "sha256", "hash": base32 })
+        );
+
+        // it's signed as the base32 form, so cache.nixos.org's signature and
+        // a new one are the same as for the original
+        let s = Store::new("/nix/store").unwrap();
+        assert_eq!(
+            fingerprint_narinfo(&s, &body).unwrap(),
+            fingerprint_narinfo(&s, YOSYS).unwrap()
+        );
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+        let checks = verify_narinfo(&s, &trusted, &body, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Valid);
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        assert_eq!(
+            sign_narinfo(&s, &keys, &body).unwrap(),
+            "t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA=="
+        );
+
+        assert_eq!(
+            normalize_hashes(&body, true).unwrap(),
+            format!("{}\n", YOSYS)
+        );
+
+        let path = "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15";
+        let err = s
+            .fingerprint_path(path, "md5-AAAA", &17680416, vec![])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::BadHashAlgo {
+                algo: "md5".to_string()
+            })
+        );
+        assert!(s
+            .fingerprint_path(path, "sha256-not!base64", &17680416, vec![])
+            .is_err());
+    }
+
+    #[test]
+    fn test_store_trailing_slash() {
+        let s = Store::new("/nix/store").unwrap();
+        let slash = Store::new("/nix/store/").unwrap();
+        assert_eq!(slash.store_path, "/nix/store");
+        assert_eq!(
+            fingerprint_narinfo(&slash, YOSYS).unwrap(),
+            fingerprint_narinfo(&s, YOSYS).unwrap()
+        );
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+        let checks = verify_narinfo(&slash, &trusted, YOSYS, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Valid);
+
+        assert!(Store::new("/").is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_path_other_algos() {
+        let s = Store::new("/nix/store").unwrap();
+        let path = "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15";
+        let sha512 = format!(
+            "sha512:{}",
+            crate::base32::encode(&sha2::Sha512::digest(b"yosys-0.15"))
+        );
+        assert_eq!(sha512.len(), 110);
+
+        let fp = s
+            .fingerprint_path(path, &sha512, &17680416, vec![])
+            .unwrap();
+        assert_eq!(fp.as_str(), format!("1;{};{};17680416;", path, sha512));
+
+        // base16 is converted, just like for sha256
+        let base16: String = sha2::Sha512::digest(b"yosys-0.15")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let fp16 = s
+            .fingerprint_path(path, &format!("sha512:{}", base16), &17680416, vec![])
+            .unwrap();
+        assert_eq!(fp16, fp);
+
+        let sha1 = format!("sha1:{}", crate::base32::encode(&[0x42; 20]));
+        assert!(s.fingerprint_path(path, &sha1, &17680416, vec![]).is_ok());
+
+        // and a whole narinfo can be signed and verified with one
+        let body = YOSYS.replace(
+            "sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3",
+            &sha512,
+        );
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let signed = append_signature(&body, &sign_narinfo(&s, &keys, &body).unwrap());
+        let trusted = TrustStore::parse(&keys.public_key()).unwrap();
+        let checks = verify_narinfo(&s, &trusted, &signed, &mut Warnings::new()).unwrap();
+        assert!(checks.iter().any(|c| c.status.is_valid()));
+
+        let err = s
+            .fingerprint_path(path, &sha512[..100], &17680416, vec![])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::BadHashLength {
+                expected: 110,
+                got: 100
+            })
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_path_store_boundary() {
+        let path = "/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1";
+        let hash = "sha256:0si0g30ksvlz953ysczn7jb0z942xzhrzwzx6h94f76r9k8269ph";
+
+        let s = Store::new("/nix/sto").unwrap();
+        assert!(s.fingerprint_path(path, hash, &64184, vec![]).is_err());
+
+        let ks = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        assert!(sign_narinfo(&s, &ks, YOSYS).is_err());
+    }
+
+    #[test]
+    fn test_store_from_store_path() {
+        let path = "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15";
+        let s = Store::from_store_path(path).unwrap();
+        assert_eq!(s.store_path, "/nix/store");
+
+        assert!(Store::from_store_path("dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15").is_err());
+        assert!(Store::from_store_path("/nix/store/").is_err());
+    }
+
+    #[test]
+    fn test_secretkey_to_publickey() {
+        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+        let pk = "t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=";
+
+        assert_eq!(sk_to_pk(sk).unwrap(), pk.to_string());
+    }
+
+    #[test]
+    fn test_secretkey_to_keypair() {
+        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+        assert!(
+            sk_to_keypair(sk).is_ok(),
+            "keypair should have been created"
+        );
+    }
+
+    #[test]
+    fn test_sign_narinfo() {
+        let input = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
+Compression: xz
+FileHash: sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds
+FileSize: 3542408
+NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
+NarSize: 17680416
+References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib 20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2 9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11 ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11 dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16 dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15 fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3 hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10 klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04 q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117 sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08
+Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv
+Sig: cache.nixos.org-1:eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA=="#;
+
+        let s = Store::new("/nix/store").unwrap();
+        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+        let keys = sk_to_keypair(sk).unwrap();
+
+        let sig = sign_narinfo(&s, &keys, input).unwrap();
+        assert_eq!("t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==", sig);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let s = Store::new("/nix/store").unwrap();
+        let computed = fingerprint_narinfo(&s, YOSYS).unwrap();
+        let fp = Fingerprint::new(computed.as_str()).unwrap();
+        assert_eq!(fp, computed);
+
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        assert_eq!(
+            base64::encode(fp.sign(&keys).to_bytes()),
+            "DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA=="
+        );
+
+        let path = "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15";
+        let hash = "sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3";
+        assert!(Fingerprint::new(&format!("1;{};{};17680416;", path, hash)).is_ok());
+        for fp in [
+            format!("2;{};{};17680416;", path, hash),
+            format!("1;{};{};17680416", path, hash),
+            format!("1;{};{};big;", path, hash),
+            format!("1;{};{};17680416;{},gcc", path, hash, path),
+            format!(
+                "1;dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15;{};17680416;",
+                hash
+            ),
+        ] {
+            assert!(Fingerprint::new(&fp).is_err(), "{}", fp);
+        }
+    }
+
+    #[test]
+    fn test_sign_narinfo_bad_store_path() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        s.check_store_path("/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15")
+            .unwrap();
+
+        let input = YOSYS.replace(
+            "StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-",
+            "StorePath: /nix/store/dw2xrnys127khw71-",
+        );
+        let err = sign_narinfo(&s, &keys, &input).unwrap_err();
+        assert!(err.to_string().starts_with("invalid store path"), "{}", err);
+        assert!(sign_narinfo_keeping_sigs(&s, &keys, &input).is_err());
+
+        for path in [
+            "/nix/store/dw2xrnys127khw71bjygg7hmny62243n",
+            "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-",
+            "/nix/store/ew2xrnys127khw71bjygg7hmny62243n-yosys-0.15",
+            "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys/bin",
+        ] {
+            assert!(s.check_store_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_missing_signed_fields() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+
+        for field in SIGNED_FIELDS {
+            let input: String = YOSYS
+                .lines()
+                .filter(|l| !l.starts_with(&format!("{}:", field)))
+                .map(|l| format!("{}\n", l))
+                .collect();
+            let missing = ParseError::MissingField {
+                name: field.to_string(),
+            };
+
+            let err = fingerprint_narinfo(&s, &input).unwrap_err();
+            assert_eq!(err.downcast_ref::<ParseError>(), Some(&missing));
+            assert!(verify_narinfo(&s, &trusted, &input, &mut Warnings::new()).is_err());
+            let err = sign_narinfo_to_json(&s, &keys, &input).unwrap_err();
+            assert_eq!(err.downcast_ref::<ParseError>(), Some(&missing));
+            let err = sign_narinfo(&s, &keys, &input).unwrap_err();
+            assert_eq!(err.downcast_ref::<ParseError>(), Some(&missing));
+        }
+
+        // a reference outside of the store is an error too, rather than a
+        // panic
+        let path = "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15";
+        let hash = "sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3";
+        assert!(s
+            .fingerprint_path(path, hash, &17680416, vec!["/gnu/store/foo"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_narinfo_counts() {
+        let s = Store::new("/nix/store").unwrap();
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+
+        let checks = verify_narinfo(&s, &trusted, YOSYS, &mut Warnings::new()).unwrap();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].host, "cache.nixos.org-1");
+        assert_eq!(checks[0].status, SigStatus::Valid);
+        assert_eq!(tally(&checks), (1, 0));
+
+        // the same signature, but claiming to be from a key nobody trusts
+        let other = YOSYS.replace("Sig: cache.nixos.org-1:", "Sig: t:");
+        let checks = verify_narinfo(&s, &trusted, &other, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Untrusted);
+        assert_eq!(tally(&checks), (0, 1));
+    }
+
+    #[test]
+    fn test_multi_store() {
+        let ms = MultiStore::parse("/nix/store:/chroot/nix/store").unwrap();
+
+        let path = "/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1";
+        let s = ms.store_for(path).unwrap();
+        assert_eq!(s.store_path, "/nix/store");
+        let fp = s.fingerprint_path(
+            path,
+            "sha256:0si0g30ksvlz953ysczn7jb0z942xzhrzwzx6h94f76r9k8269ph",
+            &64184,
+            vec![path],
+        );
+        assert!(fp.is_ok());
+
+        let path = "/chroot/nix/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1";
+        let s = ms.store_for(path).unwrap();
+        assert_eq!(s.store_path, "/chroot/nix/store");
+        let fp = s.fingerprint_path(
+            path,
+            "sha256:0si0g30ksvlz953ysczn7jb0z942xzhrzwzx6h94f76r9k8269ph",
+            &64184,
+            vec![path],
+        );
+        assert!(fp.is_ok());
+
+        assert!(ms
+            .store_for("/gnu/store/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1")
+            .is_none());
+        assert!(ms
+            .store_for("/nix/store2/009ixrgv5dylkrpx5ylba8yxqcbis5bs-libfreeaptx-0.1.1")
+            .is_none());
+    }
+
+    #[test]
+    fn test_fail_on_warning() {
+        let s = Store::new("/nix/store").unwrap();
+
+        let mut warnings = Warnings::new();
+        validate_narinfo(&s, YOSYS, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        assert!(warnings.finish(true).is_ok());
+
+        let input = format!("{}\nFoo: bar", YOSYS);
+        let mut warnings = Warnings::new();
+        validate_narinfo(&s, &input, &mut warnings).unwrap();
+        assert_eq!(warnings.messages(), ["unknown field: Foo"]);
+        assert!(warnings.finish(false).is_ok());
+        assert!(warnings.finish(true).is_err());
+    }
+
+    #[test]
+    fn test_load_binary_key_file() {
+        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+        let bin = base64::decode(sk.split_once(':').unwrap().1).unwrap();
+
+        let dir =
+            std::env::temp_dir().join(format!("narinfo-tools-test-key-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = dir.join("text.sec");
+        let binary = dir.join("t.sec");
+        std::fs::write(&text, format!("{}\n", sk)).unwrap();
+        std::fs::write(&binary, &bin).unwrap();
+
+        let s = Store::new("/nix/store").unwrap();
+        let from_text = load_secret_key_file(&text).unwrap();
+        let from_binary = load_secret_key_file(&binary).unwrap();
+        assert_eq!(from_binary.host, "t");
+        assert_eq!(
+            sign_narinfo(&s, &from_text, YOSYS).unwrap(),
+            sign_narinfo(&s, &from_binary, YOSYS).unwrap()
+        );
+
+        // a typo in a text key is reported, rather than it being loaded as
+        // a binary key made of text
+        let typo = dir.join("typo.sec");
+        std::fs::write(&typo, format!("{}A\n", sk)).unwrap();
+        assert!(load_secret_key_file(&typo).is_err());
+
+        // and the public half of a binary key has to match the secret half
+        let mut mismatched = bin.clone();
+        mismatched[32..].copy_from_slice(
+            &keypair_from_seed("t", &[0; 32])
+                .unwrap()
+                .keys
+                .public
+                .to_bytes(),
+        );
+        let mismatched_file = dir.join("t2.sec");
+        std::fs::write(&mismatched_file, &mismatched).unwrap();
+        match load_secret_key_file(&mismatched_file) {
+            Err(e) => assert_eq!(e.to_string(), "secret key doesn't match its public key"),
+            Ok(_) => panic!("loaded a mismatched keypair"),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_info_to_narinfos() {
+        let json = r#"[{"path":"/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15","url":"nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz","compression":"xz","downloadHash":"sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds","downloadSize":3542408,"narHash":"sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3","narSize":17680416,"references":["/nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib","/nix/store/20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2","/nix/store/9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11","/nix/store/ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11","/nix/store/dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16","/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15","/nix/store/fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3","/nix/store/hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10","/nix/store/klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04","/nix/store/q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117","/nix/store/sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev","/nix/store/yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08"],"deriver":"/nix/store/x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv","signatures":["cache.nixos.org-1:eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA=="]}]"#;
+
+        let narinfos = path_info_to_narinfos(json).unwrap();
+        assert_eq!(narinfos.len(), 1);
+        assert_eq!(narinfos[0].to_string(), format!("{}\n", YOSYS));
+
+        // newer versions of nix key the output by path instead
+        let keyed = format!(
+            "{{\"/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15\":{}}}",
+            json.trim_start_matches('[').trim_end_matches(']')
+        );
+        assert_eq!(path_info_to_narinfos(&keyed).unwrap(), narinfos);
+
+        let outside = json.replace("\"/nix/store/18fz", "\"/gnu/store/18fz");
+        assert!(path_info_to_narinfos(&outside).is_err());
+    }
+
+    #[test]
+    fn test_path_info_to_narinfos_local_store() {
+        // what `nix path-info --json` says about a path in the local store:
+        // no url or compression, and an SRI narHash
+        let json = r#"{"/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15":{"ca":null,"deriver":"/nix/store/x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv","narHash":"sha256-o0LYZHlc7oMq/UOa9QZyP5WBmmEWK5TKvbIQPSr+/tY=","narSize":17680416,"references":["/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15"],"registrationTime":1650000000,"signatures":[],"ultimate":false,"valid":true}}"#;
+
+        let narinfos = path_info_to_narinfos(json).unwrap();
+        assert_eq!(narinfos.len(), 1);
+        let info = &narinfos[0];
+        assert_eq!(
+            info.nar_hash,
+            "sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3"
+        );
+        assert_eq!(
+            info.url,
+            "nar/1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3.nar"
+        );
+        assert_eq!(info.compression.as_deref(), Some("none"));
+        assert_eq!(info.file_hash, None);
+
+        let no_hash = json.replace("\"narHash\"", "\"narhash\"");
+        assert!(path_info_to_narinfos(&no_hash).is_err());
+    }
+
+    #[test]
+    fn test_trust_store_shared() {
+        let s = Store::new("/nix/store").unwrap();
+        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+        let pk = "t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=";
+        let trusted = TrustStore::parse(&format!("{} {}", CACHE_NIXOS_ORG_PK, pk)).unwrap();
+        assert_eq!(trusted.keys_for("t").len(), 1);
+        assert!(trusted.keys_for("u").is_empty());
+
+        // sign with both keys, and tamper with every third record
+        let keys = sk_to_keypair(sk).unwrap();
+        let sig = sign_narinfo(&s, &keys, YOSYS).unwrap();
+        let records: Vec<String> = (0..30)
+            .map(|i| {
+                let body = format!("{}\nSig: {}", YOSYS, sig);
+                if i % 3 == 0 {
+                    body.replace("NarSize: 17680416", "NarSize: 17680417")
+                } else {
+                    body
+                }
+            })
+            .collect();
+
+        for (i, body) in records.iter().enumerate() {
+            let checks = verify_narinfo(&s, &trusted, body, &mut Warnings::new()).unwrap();
+            if i % 3 == 0 {
+                assert_eq!(tally(&checks), (0, 2));
+                assert_eq!(checks[0].status, SigStatus::Invalid);
+            } else {
+                assert_eq!(tally(&checks), (2, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sign_narinfo_to_json() {
+        let s = Store::new("/nix/store").unwrap();
+        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+        let keys = sk_to_keypair(sk).unwrap();
+
+        let json = sign_narinfo_to_json(&s, &keys, YOSYS).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            v["storePath"],
+            "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15"
+        );
+        assert_eq!(v["sig"], "t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==");
+        assert_eq!(v["fingerprint"], "1;/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15;sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3;17680416;/nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib,/nix/store/20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2,/nix/store/9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11,/nix/store/ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11,/nix/store/dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16,/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15,/nix/store/fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3,/nix/store/hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10,/nix/store/klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04,/nix/store/q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117,/nix/store/sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev,/nix/store/yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08");
+    }
+
+    #[test]
+    fn test_narinfo_to_json_abs_refs() {
+        let input = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv"#;
+
+        let mut output = String::new();
+        let opts = JsonOptions::default();
+        narinfo_to_json_with(input.to_string(), &mut output, &opts, &mut Warnings::new()).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            v["Deriver"],
+            "x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv"
+        );
+        assert_eq!(
+            v["References"][0],
+            "18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib"
+        );
+
+        let s = Store::new("/nix/store").unwrap();
+        let mut output = String::new();
+        let opts = JsonOptions {
+            abs_refs: Some(&s),
+            ..Default::default()
+        };
+        narinfo_to_json_with(input.to_string(), &mut output, &opts, &mut Warnings::new()).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            v["Deriver"],
+            "/nix/store/x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv"
+        );
+        assert_eq!(
+            v["References"][0],
+            "/nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib"
+        );
+        assert_eq!(
+            v["StorePath"],
+            "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15"
+        );
+
+        // a deriver that's already absolute isn't prefixed twice
+        let absolute = input.replace("Deriver: ", "Deriver: /nix/store/");
+        let mut output = String::new();
+        narinfo_to_json_with(absolute, &mut output, &opts, &mut Warnings::new()).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            v["Deriver"],
+            "/nix/store/x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv"
+        );
+    }
+
+    #[test]
+    fn test_read_chunked() {
+        for chunk_size in [1, 7, 4096, DEFAULT_CHUNK_SIZE] {
+            let content = read_chunked(YOSYS.as_bytes(), chunk_size, false).unwrap();
+            assert_eq!(content, YOSYS);
+        }
+    }
+
+    #[test]
+    fn test_narinfo_to_json_refs_detailed() {
+        let input = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15"#;
+        let expected = r#"{ "StorePath": "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15", "References": [ { "path": "/nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib", "hash": "18fz9jnhmfkzkh6p1iwwwng4i7x4rag7", "name": "gcc-10.3.0-lib" }, { "path": "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15", "hash": "dw2xrnys127khw71bjygg7hmny62243n", "name": "yosys-0.15" } ] }"#;
+
+        let s = Store::new("/nix/store").unwrap();
+        let opts = JsonOptions {
+            abs_refs: Some(&s),
+            refs_detailed: true,
+            ..JsonOptions::default()
+        };
+        let mut output = String::new();
+        narinfo_to_json_with(input.to_string(), &mut output, &opts, &mut Warnings::new()).unwrap();
+        assert_eq!(expected, output.trim());
+    }
+
+    #[test]
+    fn test_verify_narinfo_show_key() {
+        let s = Store::new("/nix/store").unwrap();
+        let old = "cache.nixos.org-1:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=";
+        let trusted = TrustStore::parse(&format!("{} {}", old, CACHE_NIXOS_ORG_PK)).unwrap();
+
+        // two keys are trusted for the same host, so make sure the right one
+        // is reported
+        let checks = verify_narinfo(&s, &trusted, YOSYS, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Valid);
+        assert_eq!(
+            checks[0].key_base64().unwrap(),
+            CACHE_NIXOS_ORG_PK.split_once(':').unwrap().1
+        );
+
+        let trusted = TrustStore::parse(old).unwrap();
+        let checks = verify_narinfo(&s, &trusted, YOSYS, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Invalid);
+        assert_eq!(checks[0].key_base64(), None);
+    }
+
+    #[test]
+    fn test_read_chunked_lossy() {
+        // corrupt the 'x' of the '.nar.xz' extension
+        let mut input = YOSYS.as_bytes().to_vec();
+        input[YOSYS.find(".nar.xz").unwrap() + 5] = 0xff;
+
+        assert!(read_chunked(&input[..], DEFAULT_CHUNK_SIZE, false).is_err());
+        let content = read_chunked(&input[..], DEFAULT_CHUNK_SIZE, true).unwrap();
+
+        let mut output = String::new();
+        crate::narinfo::narinfo_to_json(content, &mut output);
+        let v: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            v["URL"],
+            "nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.\u{fffd}z"
+        );
+        assert_eq!(v["NarSize"], 17680416);
+    }
+
+    #[test]
+    fn test_store_path_hash() {
+        // nix-repl> builtins.toFile "foo" "bar"
+        // "/nix/store/vxjiwkjkn7x4079qvh1jkl5pn05j2aw0-foo"
+        let s = Store::new("/nix/store").unwrap();
+        let contents: String = Sha256::digest(b"bar")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let hash = format!("sha256:{}", contents);
+        assert_eq!(
+            s.store_path_hash("text", &hash, "foo").unwrap(),
+            "vxjiwkjkn7x4079qvh1jkl5pn05j2aw0"
+        );
+
+        assert!(s.store_path_hash("text", "sha256:abc", "foo").is_err());
+    }
+
+    #[test]
+    fn test_check_ca_store_path() {
+        // nix-repl> builtins.toFile "foo" "bar"
+        // "/nix/store/vxjiwkjkn7x4079qvh1jkl5pn05j2aw0-foo"
+        let s = Store::new("/nix/store").unwrap();
+        let text = r#"StorePath: /nix/store/vxjiwkjkn7x4079qvh1jkl5pn05j2aw0-foo
+URL: nar/1xngcmsdkdvaycaqjfzvq4gr3gib56p2l8illglnsqzhpss47pxz.nar
+Compression: none
+NarHash: sha256:1xngcmsdkdvaycaqjfzvq4gr3gib56p2l8illglnsqzhpss47pxz
+NarSize: 120
+References:
+CA: text:sha256:1fcgpy7vc4ammr7s17j2xq88scswkgz23dqzc04g8sx5vcp2pppw"#;
+        assert!(check_ca_store_path(&s, text).unwrap());
+
+        // a different name, or different contents, is a different path
+        assert!(check_ca_store_path(&s, &text.replace("-foo", "-fop")).is_err());
+        let tampered = text.replace("text:sha256:1fcg", "text:sha256:0fcg");
+        let err = check_ca_store_path(&s, &tampered).unwrap_err();
+        assert!(err.to_string().contains("expected hash "));
+
+        // the same file again, but added recursively (i.e. as a NAR) as `bar`
+        let source = text
+            .replace(
+                "vxjiwkjkn7x4079qvh1jkl5pn05j2aw0-foo",
+                "2hn3fvrvs9f9xy1c65xvixn0jcmrjn27-bar",
+            )
+            .replace(
+                "text:sha256:1fcgpy7vc4ammr7s17j2xq88scswkgz23dqzc04g8sx5vcp2pppw",
+                "fixed:r:sha256:1xngcmsdkdvaycaqjfzvq4gr3gib56p2l8illglnsqzhpss47pxz",
+            );
+        assert!(check_ca_store_path(&s, &source).unwrap());
+        let err = check_ca_store_path(
+            &s,
+            &source.replace("NarHash: sha256:1x", "NarHash: sha256:0x"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("NarHash "));
+
+        // nothing to check for an input-addressed path
+        assert!(!check_ca_store_path(&s, YOSYS).unwrap());
+    }
+
+    #[test]
+    fn test_sign_narinfo_uppercase_algo() {
+        let s = Store::new("/nix/store").unwrap();
+        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+        let keys = sk_to_keypair(sk).unwrap();
+
+        let input = YOSYS.replace("NarHash: sha256:", "NarHash: SHA256:");
+        assert_eq!(
+            sign_narinfo(&s, &keys, &input).unwrap(),
+            sign_narinfo(&s, &keys, YOSYS).unwrap()
+        );
+        assert_eq!(normalize_hash_algo("Sha256:1mpyzqm3"), "sha256:1mpyzqm3");
+    }
+
+    #[test]
+    fn test_diff_narinfos_abs_refs() {
+        let refs = find_field(YOSYS, "References").unwrap();
+        let mut abs: Vec<String> = refs
+            .split_whitespace()
+            .map(|r| format!("/nix/store/{}", r))
+            .collect();
+        abs.reverse();
+        let absolute = YOSYS.replace(refs, &abs.join(" "));
+        assert!(diff_narinfos(YOSYS, &absolute).is_empty());
+
+        let different = absolute.replace("NarSize: 17680416", "NarSize: 17680417");
+        assert_eq!(
+            diff_narinfos(YOSYS, &different),
+            vec![FieldDiff {
+                field: "NarSize".to_string(),
+                left: Some("17680416".to_string()),
+                right: Some("17680417".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sign_narinfo_ephemeral() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = Keys::generate("ephemeral-1");
+        let pk = keys.public_key();
+        assert!(pk.starts_with("ephemeral-1:"));
+
+        let sig = sign_narinfo(&s, &keys, YOSYS).unwrap();
+        let body = format!("{}\nSig: {}", YOSYS, sig);
+
+        let trusted = TrustStore::parse(&pk).unwrap();
+        let checks = verify_narinfo(&s, &trusted, &body, &mut Warnings::new()).unwrap();
+        assert_eq!(tally(&checks), (1, 1));
+        assert_eq!(checks[1].host, "ephemeral-1");
+        assert_eq!(checks[1].status, SigStatus::Valid);
+
+        // and every key really is fresh
+        assert_ne!(Keys::generate("ephemeral-1").public_key(), pk);
+    }
+
+    #[test]
+    fn test_query_field_case_insensitive() {
+        assert_eq!(query_field(YOSYS, "narsize"), vec!["17680416"]);
+        assert_eq!(query_field(YOSYS, "NarSize"), vec!["17680416"]);
+        assert_eq!(
+            query_field(YOSYS, "url"),
+            vec!["nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz"]
+        );
+        assert!(query_field(YOSYS, "ca").is_empty());
+        assert_eq!(canonical_field("STOREPATH"), Some("StorePath"));
+        assert_eq!(canonical_field("Foo"), None);
+    }
+
+    #[test]
+    fn test_field_presence() {
+        let presence = field_presence(YOSYS);
+        assert_eq!(presence.len(), KNOWN_FIELDS.len());
+        assert!(presence.contains(&("StorePath", true)));
+        assert!(presence.contains(&("NarHash", true)));
+        assert!(presence.contains(&("CA", false)));
+
+        let trimmed: String = YOSYS
+            .lines()
+            .filter(|x| !x.starts_with("NarHash:"))
+            .map(|x| format!("{}\n", x))
+            .collect();
+        let presence = field_presence(&trimmed);
+        assert!(presence.contains(&("StorePath", true)));
+        assert!(presence.contains(&("NarHash", false)));
+    }
+
+    #[test]
+    fn test_narinfo_to_json_writer() {
+        let opts = JsonOptions {
+            refs_detailed: true,
+            ..JsonOptions::default()
+        };
+
+        let mut string = String::new();
+        narinfo_to_json_with(YOSYS.to_string(), &mut string, &opts, &mut Warnings::new()).unwrap();
+
+        let mut bytes: Vec<u8> = Vec::new();
+        narinfo_to_json_writer(YOSYS, &mut bytes, &opts, &mut Warnings::new()).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), string);
+    }
+
+    #[test]
+    fn test_builder_compression_default() {
+        let builder = || {
+            NarInfoBuilder::new()
+                .store_path("/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15")
+                .url("nar/foo.nar.zst")
+                .nar_hash("sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3")
+                .nar_size(17680416)
+                .compression_default("zstd")
+        };
+
+        let info = builder().build().unwrap().to_string();
+        assert!(info.contains("Compression: zstd\n"));
+
+        let info = builder().compression("xz").build().unwrap().to_string();
+        assert!(info.contains("Compression: xz\n"));
+    }
+
+    #[test]
+    fn test_verify_narinfo_unpadded_sig() {
+        let s = Store::new("/nix/store").unwrap();
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+
+        let unpadded = YOSYS.trim_end_matches('=').to_string();
+        assert_ne!(unpadded, YOSYS);
+        let checks = verify_narinfo(&s, &trusted, &unpadded, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Valid);
+
+        // and the JSON puts the padding back
+        let mut padded = String::new();
+        let mut output = String::new();
+        narinfo_to_json_with(
+            YOSYS.to_string(),
+            &mut padded,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        narinfo_to_json_with(
+            unpadded,
+            &mut output,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        assert_eq!(output, padded);
+    }
+
+    #[test]
+    fn test_narinfo_from_str() {
+        let info: NarInfo = YOSYS.parse().unwrap();
+        assert_eq!(
+            info.store_path,
+            "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15"
+        );
+        assert_eq!(info.compression.as_deref(), Some("xz"));
+        assert_eq!(info.file_size, Some(3542408));
+        assert_eq!(info.nar_size, 17680416);
+        assert_eq!(info.references.len(), 12);
+        assert_eq!(
+            info.deriver.as_deref(),
+            Some("x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv")
+        );
+        assert_eq!(info.sigs.len(), 1);
+        assert_eq!(info.sigs[0].0, "cache.nixos.org-1");
+        assert!(info.extra.is_empty());
+        assert_eq!(info.to_string(), format!("{}\n", YOSYS));
+
+        let minimal = "StorePath: /nix/store/7dgl0pd5dxnj3hmz2c1cp9xzbdlhwxj2-hello
+URL: nar/0nya2hjn923syqd433mabf0rabla032jajqil5r8lm972qk07z71.nar
+NarHash: sha256:0nya2hjn923syqd433mabf0rabla032jajqil5r8lm972qk07z71
+NarSize: 128
+References:
+System: x86_64-linux
+";
+        let info: NarInfo = minimal.parse().unwrap();
+        assert_eq!(info.compression, None);
+        assert_eq!(info.file_hash, None);
+        assert_eq!(info.file_size, None);
+        assert!(info.references.is_empty());
+        assert_eq!(info.deriver, None);
+        assert!(info.sigs.is_empty());
+        assert_eq!(info.extra["System"], "x86_64-linux");
+
+        let missing = minimal.replace("NarSize: 128\n", "");
+        let err = missing.parse::<NarInfo>().unwrap_err();
+        assert_eq!(err.to_string(), "missing required field: NarSize");
+    }
+
+    #[test]
+    fn test_json_to_narinfo() {
+        let mut json = String::new();
+        crate::narinfo::narinfo_to_json(YOSYS.to_string(), &mut json);
+        let info = json_to_narinfo(&json).unwrap();
+        assert_eq!(info, YOSYS.parse().unwrap());
+        assert_eq!(info.to_string(), format!("{}\n", YOSYS));
+
+        // absolute references and grouped signatures are undone too
+        let s = Store::new("/nix/store").unwrap();
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+        let opts = JsonOptions {
+            abs_refs: Some(&s),
+            refs_detailed: true,
+            group_sigs: Some((&s, &trusted)),
+            ..JsonOptions::default()
+        };
+        let mut out = Vec::new();
+        narinfo_to_json_writer(YOSYS, &mut out, &opts, &mut Warnings::new()).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.contains(r#""valid": { "cache.nixos.org-1""#));
+        assert_eq!(json_to_narinfo(&json).unwrap(), info);
+
+        assert!(json_to_narinfo("[]").is_err());
+        let err = json_to_narinfo(r#"{ "StorePath": "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15", "NarSize": "lots" }"#).unwrap_err();
+        assert_eq!(err.to_string(), r#"NarSize is not a number: "lots""#);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |body: &str| body.parse::<NarInfo>().unwrap_err();
+        assert_eq!(
+            parse(&YOSYS.replace("NarSize: 17680416\n", "")),
+            ParseError::MissingField {
+                name: "NarSize".into()
+            }
+        );
+        assert_eq!(
+            parse(&YOSYS.replace("NarSize: 17680416", "NarSize: big")),
+            ParseError::BadNumber {
+                field: "NarSize".into(),
+                value: "big".into()
+            }
+        );
+        assert_eq!(
+            parse(&format!("{}\nSig: cache.nixos.org-1:", YOSYS)),
+            ParseError::BadSig {
+                sig: "cache.nixos.org-1:".into()
+            }
+        );
+
+        // the errors from signing are still parse errors underneath
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let sign = |from: &str, to: &str| {
+            let body = YOSYS.replace(from, to);
+            let err = sign_narinfo(&s, &keys, &body).unwrap_err();
+            err.downcast_ref::<ParseError>().cloned()
+        };
+        let hash = "sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3";
+        assert_eq!(
+            sign(
+                hash,
+                "md5:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3"
+            ),
+            Some(ParseError::BadHashAlgo { algo: "md5".into() })
+        );
+        assert_eq!(
+            sign(hash, "sha256:1mpyzqm3s45jpp598"),
+            Some(ParseError::BadHashLength {
+                expected: 59,
+                got: 24
+            })
+        );
+        assert_eq!(
+            sign(
+                hash,
+                "sha256:empyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3"
+            ),
+            Some(ParseError::InvalidBase32 { pos: 0, ch: 'e' })
+        );
+        assert_eq!(
+            sign("NarSize: 17680416", "NarSize: -1"),
+            Some(ParseError::BadNumber {
+                field: "NarSize".into(),
+                value: "-1".into()
+            })
+        );
+
+        let err =
+            validate_narinfo(&s, &format!("{}\noops", YOSYS), &mut Warnings::new()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::MissingColon {
+                line: "oops".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_rewrite_store_dir() {
+        let (from, to) = (
+            Store::new("/nix/store").unwrap(),
+            Store::new("/new/store").unwrap(),
+        );
+        let body = YOSYS
+            .replace(
+                "References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7",
+                "References: /nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7",
+            )
+            .replace("Deriver: ", "Deriver: /nix/store/");
+        let mut info: NarInfo = body.parse().unwrap();
+        info.rewrite_store_dir(&from, &to).unwrap();
+
+        assert_eq!(
+            info.store_path,
+            "/new/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15"
+        );
+        assert_eq!(
+            info.references[0],
+            "/new/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib"
+        );
+        assert_eq!(
+            info.references[1],
+            "20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2"
+        );
+        assert_eq!(
+            info.deriver.as_deref(),
+            Some("/new/store/x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv")
+        );
+        assert!(info.sigs.is_empty());
+        assert!(!info.to_string().contains("/nix/store"));
+
+        // and it can be signed again for the new store
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let fp = fingerprint_narinfo(&to, &info.to_string()).unwrap();
+        assert!(fp
+            .as_str()
+            .starts_with("1;/new/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15;"));
+        assert!(sign_narinfo(&to, &keys, &info.to_string()).is_ok());
+
+        let mut elsewhere: NarInfo = YOSYS.replace("/nix/store", "/other").parse().unwrap();
+        assert!(elsewhere.rewrite_store_dir(&from, &to).is_err());
+    }
+
+    #[test]
+    fn test_check_strict_colons() {
+        check_strict_colons(YOSYS).unwrap();
+        check_strict_colons("StorePath: /nix/store/foo\nReferences: \n").unwrap();
+
+        for line in [
+            "StorePath :  /nix/store/foo",
+            " StorePath: /nix/store/foo",
+            "StorePath:/nix/store/foo",
+            "StorePath: /nix/store/foo ",
+        ] {
+            let err = check_strict_colons(line).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ParseError>(),
+                Some(&ParseError::BadSpacing {
+                    line: line.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_required_fields() {
+        let fields = |names: &[&str]| names.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        check_required_fields(YOSYS, &fields(&["StorePath", "narhash", "References"])).unwrap();
+        check_required_fields(YOSYS, &[]).unwrap();
+
+        let err = check_required_fields(YOSYS, &fields(&["NarSize", "ca"])).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::MissingField {
+                name: "CA".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_narinfo_to_json_strict() {
+        let doubled = format!("{}\nNarSize: 42\n", YOSYS);
+
+        let mut buf = Vec::new();
+        narinfo_to_json_writer(
+            &doubled,
+            &mut buf,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(json["NarSize"], 42);
+
+        let opts = JsonOptions {
+            strict: true,
+            ..JsonOptions::default()
+        };
+        let err = narinfo_to_json_writer(&doubled, &mut Vec::new(), &opts, &mut Warnings::new())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::DuplicateField {
+                name: "NarSize".to_string()
+            })
+        );
+
+        // and the same through the string version, which gives back the
+        // error rather than panicking, and adds nothing to the output
+        let mut output = String::new();
+        let err = narinfo_to_json_with(
+            "NarSize: 1\nNarSize: 2\n".to_string(),
+            &mut output,
+            &opts,
+            &mut Warnings::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::DuplicateField {
+                name: "NarSize".to_string()
+            })
+        );
+        assert!(output.is_empty());
+
+        // several signatures are fine, though
+        let signed = format!("{}\nSig: a:b\nSig: c:d\n", YOSYS);
+        narinfo_to_json_writer(&signed, &mut Vec::new(), &opts, &mut Warnings::new()).unwrap();
+
+        // grouping the signatures needs a fingerprint, and so every field
+        let s = Store::new("/nix/store").unwrap();
+        let trusted = TrustStore::new();
+        let opts = JsonOptions {
+            group_sigs: Some((&s, &trusted)),
+            ..JsonOptions::default()
+        };
+        let unfingerprintable = YOSYS.replace("NarHash:", "Narhash:");
+        assert!(
+            narinfo_to_json_with(unfingerprintable, &mut output, &opts, &mut Warnings::new())
+                .is_err()
+        );
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_narinfos_to_ndjson() {
+        let other = YOSYS.replace("yosys-0.15", "yosys-0.16");
+        let input = format!("{}\n\n{}\n\n\n{}\n", YOSYS, other, YOSYS);
+        let mut out = Vec::new();
+        let count = narinfos_to_ndjson(
+            input.as_bytes(),
+            &mut out,
+            &JsonOptions::default(),
+            false,
+            None,
+            |_| Ok(()),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        assert_eq!(count, 3);
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let mut expected = String::new();
+        narinfo_to_json(YOSYS.to_string(), &mut expected);
+        assert_eq!(lines[0], expected.trim_end());
+        assert_eq!(lines[2], expected.trim_end());
+
+        let json: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(
+            json["StorePath"],
+            "/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.16"
+        );
+    }
+
+    #[test]
+    fn test_wrapped_base64() {
+        let sig = "t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==";
+        let wrapped = "t:DWUrR00frjSmaW5lRGmLxQ4Tptkgg NxiqDtkfZsJcSfleCIT4Qaw+orizNxxnP mhpLOeVhws5BjPzBznzgzkCA==";
+        let body = append_signature(YOSYS, wrapped);
+
+        let trusted = TrustStore::parse("t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=").unwrap();
+        let checks = verify_narinfo(
+            &Store::new("/nix/store").unwrap(),
+            &trusted,
+            &body,
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        let ours = checks.iter().find(|c| c.host == "t").unwrap();
+        assert_eq!(ours.status, SigStatus::Valid);
+
+        // and it always comes back out as a single line
+        let info: NarInfo = body.parse().unwrap();
+        assert!(info.to_string().contains(&format!("Sig: {}\n", sig)));
+        let mut json = String::new();
+        narinfo_to_json_with(
+            body,
+            &mut json,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        assert!(json.contains(sig.split_once(':').unwrap().1));
+
+        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pf gERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+        assert_eq!(
+            sk_to_pk(sk).unwrap(),
+            "t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U="
+        );
+        parse_public_key("t:cA9qX4BERfU6VFLjQW+XOhsAU S1f54lu/GFI67dou9U=").unwrap();
+    }
+
+    #[test]
+    fn test_keypair_from_seed() {
+        let seed = parse_seed(&"2a".repeat(32)).unwrap();
+        let keys = keypair_from_seed("t", &seed).unwrap();
+        let again = keypair_from_seed("t", &seed).unwrap();
+        assert_eq!(keys.secret_key(), again.secret_key());
+        assert_eq!(keys.public_key(), again.public_key());
+        assert_eq!(sk_to_pk(&keys.secret_key()).unwrap(), keys.public_key());
+
+        let other = keypair_from_seed("t", &[0; 32]).unwrap();
+        assert_ne!(keys.public_key(), other.public_key());
+
+        assert!(parse_seed("2a2a").is_err());
+        assert!(parse_seed(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_sign_narinfo_with_keys() {
+        let s = Store::new("/nix/store").unwrap();
+        let old = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let new = Keys::generate("t-2");
+        let keys = [old, new];
+
+        let sigs = sign_narinfo_with_keys(&s, &keys, YOSYS).unwrap();
+        assert_eq!(sigs.len(), 3);
+        assert!(sigs[0].starts_with("cache.nixos.org-1:"));
+        assert_eq!(sigs[1], "t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==");
+        assert!(sigs[2].starts_with("t-2:"));
+
+        // and doing it again doesn't add any more
+        let signed = replace_signatures(YOSYS, &sigs);
+        assert_eq!(sign_narinfo_with_keys(&s, &keys, &signed).unwrap(), sigs);
+    }
+
+    #[test]
+    fn test_json_buffer() {
+        let mut buf = JsonBuffer::new();
+        let opts = JsonOptions::default();
+        for i in 0..200 {
+            // alternate between a big record and a small one, so that a
+            // stale tail of the previous one would show up
+            let body = if i % 2 == 0 {
+                YOSYS.replace("17680416", &i.to_string())
+            } else {
+                format!("StorePath: /nix/store/{}-x\n", i)
+            };
+            let mut expected = String::new();
+            narinfo_to_json_with(body.clone(), &mut expected, &opts, &mut Warnings::new()).unwrap();
+
+            let json = buf.convert(&body, &opts, &mut Warnings::new()).unwrap();
+            assert_eq!(json, expected);
+            let v: serde_json::Value = serde_json::from_str(json).unwrap();
+            if i % 2 == 0 {
+                assert_eq!(v["NarSize"], i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_references_absolute() {
+        let s = Store::new("/nix/store").unwrap();
+        let info: NarInfo = YOSYS.parse().unwrap();
+
+        let refs = info.references_absolute(&s).unwrap();
+        assert_eq!(refs.len(), 12);
+        assert_eq!(
+            refs[0],
+            "/nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib"
+        );
+        assert_eq!(
+            refs[11],
+            "/nix/store/yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08"
+        );
+        assert!(refs.iter().all(|r| s.contains(r)));
+
+        assert_eq!(
+            info.fingerprint(&s).unwrap(),
+            fingerprint_narinfo(&s, YOSYS).unwrap()
+        );
+
+        // the typed and text fingerprints agree however the references are
+        // written: out of order, duplicated, or some of them absolute
+        let refs = find_field(YOSYS, "References").unwrap();
+        let mut shuffled: Vec<String> = refs.split_whitespace().rev().map(String::from).collect();
+        shuffled.push(shuffled[0].clone());
+        shuffled[1] = format!("/nix/store/{}", shuffled[1]);
+        let input = YOSYS.replace(refs, &shuffled.join(" "));
+        let info: NarInfo = input.parse().unwrap();
+        assert_eq!(
+            info.fingerprint(&s).unwrap(),
+            fingerprint_narinfo(&s, &input).unwrap()
+        );
+        assert_eq!(
+            info.fingerprint(&s).unwrap(),
+            fingerprint_narinfo(&s, YOSYS).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_verify_narinfo_unsigned() {
+        let s = Store::new("/nix/store").unwrap();
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+
+        let unsigned: String = YOSYS
+            .lines()
+            .filter(|l| !l.starts_with("Sig:"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        let checks = verify_narinfo(&s, &trusted, &unsigned, &mut Warnings::new()).unwrap();
+        assert!(checks.is_empty());
+        let err = verified(&checks, false).unwrap_err();
+        assert_eq!(err.to_string(), "no signatures present");
+        assert!(verified(&checks, true).unwrap());
+
+        let checks = verify_narinfo(&s, &trusted, YOSYS, &mut Warnings::new()).unwrap();
+        assert!(verified(&checks, false).unwrap());
+    }
+
+    #[test]
+    fn test_generate_key_roundtrip() {
+        let keys = Keys::generate("cache.example.org-1");
+        let (sk, pk) = (keys.secret_key(), keys.public_key());
+        assert!(sk.starts_with("cache.example.org-1:"));
+        assert_eq!(sk_to_pk(&sk).unwrap(), pk);
+
+        let parsed = sk_to_keypair(&sk).unwrap();
+        assert_eq!(parsed.host, "cache.example.org-1");
+        assert_eq!(parsed.secret_key(), sk);
+        let (host, _) = parse_public_key(&pk).unwrap();
+        assert_eq!(host, "cache.example.org-1");
+    }
+
+    #[test]
+    fn test_write_keys_file() {
+        let dir = std::env::temp_dir().join(format!(
+            "narinfo-tools-test-keys-file-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("cache.example.org-1");
+        let keys = Keys::generate("cache.example.org-1");
+        write_keys_file(&base, &keys, false).unwrap();
+
+        let sec = std::fs::read_to_string(dir.join("cache.example.org-1.sec")).unwrap();
+        let pub_ = std::fs::read_to_string(dir.join("cache.example.org-1.pub")).unwrap();
+        assert_eq!(sec, keys.secret_key());
+        assert_eq!(pub_, keys.public_key());
+        assert_eq!(sk_to_pk(&sec).unwrap(), pub_);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let meta = std::fs::metadata(dir.join("cache.example.org-1.sec")).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        }
+
+        // existing keys are left alone, unless forced
+        let other = Keys::generate("cache.example.org-1");
+        let err = write_keys_file(&base, &other, false).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        assert_eq!(
+            load_secret_key_file(&dir.join("cache.example.org-1.sec"))
+                .unwrap()
+                .public_key(),
+            pub_
+        );
+        write_keys_file(&base, &other, true).unwrap();
+        let pub_ = std::fs::read_to_string(dir.join("cache.example.org-1.pub")).unwrap();
+        assert_eq!(pub_, other.public_key());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_line_without_colon() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let input = YOSYS.replacen("\nURL:", "\noops\nURL:", 1);
+        assert_ne!(input, YOSYS);
+
+        assert_eq!(
+            sign_narinfo(&s, &keys, &input).unwrap(),
+            sign_narinfo(&s, &keys, YOSYS).unwrap()
+        );
+
+        let json = |body: &str, warnings: &mut Warnings| {
+            let mut out = Vec::new();
+            narinfo_to_json_writer(body, &mut out, &JsonOptions::default(), warnings).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let mut warnings = Warnings::new();
+        assert_eq!(
+            json(&input, &mut warnings),
+            json(YOSYS, &mut Warnings::new())
+        );
+        assert_eq!(
+            warnings.messages(),
+            ["ignoring line without a field name: oops"]
+        );
+    }
+
+    #[test]
+    fn test_sign_narinfo_keeping_sigs() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let ours = "t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==";
+        let theirs = YOSYS.lines().last().unwrap().strip_prefix("Sig: ").unwrap();
+
+        let sigs = sign_narinfo_keeping_sigs(&s, &keys, YOSYS).unwrap();
+        assert_eq!(sigs, [theirs, ours]);
+        let once = replace_signatures(YOSYS, &sigs);
+        assert_eq!(once, format!("{}\nSig: {}\n", YOSYS, ours));
+
+        // signing again with the same host changes nothing
+        let sigs = sign_narinfo_keeping_sigs(&s, &keys, &once).unwrap();
+        assert_eq!(sigs, [theirs, ours]);
+        let twice = replace_signatures(&once, &sigs);
+        assert_eq!(twice, once);
+        assert_eq!(twice.matches("Sig: t:").count(), 1);
+
+        // a kept signature comes out looking just like a new one would
+        let untidy = once.replace(
+            &format!("Sig: {}", theirs),
+            &format!(
+                "Sig:  {} : {}",
+                theirs.split_once(':').unwrap().0,
+                theirs.split_once(':').unwrap().1.trim_end_matches('=')
+            ),
+        );
+        assert_ne!(untidy, once);
+        let sigs = sign_narinfo_keeping_sigs(&s, &keys, &untidy).unwrap();
+        assert_eq!(sigs, [theirs, ours]);
+        assert_eq!(replace_signatures(&untidy, &sigs), once);
+    }
+
+    #[test]
+    fn test_sign_narinfo_crlf() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let sig = "t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==";
+
+        let input = format!("{}\r\n", YOSYS.replace('\n', "\r\n"));
+        assert_eq!(sign_narinfo(&s, &keys, &input).unwrap(), sig);
+        assert_eq!(
+            append_signature(&input, sig),
+            format!("{}\nSig: {}", YOSYS, sig)
+        );
+    }
+
+    #[test]
+    fn test_normalize_hashes() {
+        let base16 = YOSYS
+            .replace(
+                "sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3",
+                "sha256:a342d864795cee832afd439af506723f95819a61162b94cabdb2103d2afefed6",
+            )
+            .replace(
+                "sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds",
+                "SHA256:ba81e9d9fe5eaf62e3984c54e42a77c3739a7e3ca9f36c8e244e2fa28631cc1b",
+            );
+        assert_ne!(base16, YOSYS);
+
+        let unsigned: String = YOSYS
+            .lines()
+            .filter(|l| !l.starts_with("Sig:"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        assert_eq!(normalize_hashes(&base16, false).unwrap(), unsigned);
+        assert_eq!(
+            normalize_hashes(&base16, true).unwrap(),
+            format!("{}\n", YOSYS)
+        );
+
+        // already base32, so nothing to do
+        assert_eq!(
+            normalize_hashes(YOSYS, true).unwrap(),
+            format!("{}\n", YOSYS)
+        );
+    }
+
+    /// A NAR holding a single regular file, laid out by hand.
+    fn nar_of_file(contents: &[u8]) -> Vec<u8> {
+        let mut nar = Vec::new();
+        let strings: [&[u8]; 7] = [
+            b"nix-archive-1",
+            b"(",
+            b"type",
+            b"regular",
+            b"contents",
+            contents,
+            b")",
+        ];
+        for s in strings.iter() {
+            nar.extend_from_slice(&(s.len() as u64).to_le_bytes());
+            nar.extend_from_slice(s);
+            nar.resize(nar.len() + (8 - s.len() % 8) % 8, 0);
+        }
+        nar
+    }
+
+    #[test]
+    fn test_check_nar() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let nar = nar_of_file(b"hello, world\n");
+        assert_eq!(
+            hash_nar(nar.as_slice(), 7).unwrap(),
+            (
+                "sha256:0nya2hjn923syqd433mabf0rabla032jajqil5r8lm972qk07z71".to_string(),
+                128
+            )
+        );
+
+        let unsigned = r#"StorePath: /nix/store/7dgl0pd5dxnj3hmz2c1cp9xzbdlhwxj2-hello
+URL: nar/0nya2hjn923syqd433mabf0rabla032jajqil5r8lm972qk07z71.nar
+Compression: none
+NarHash: sha256:0nya2hjn923syqd433mabf0rabla032jajqil5r8lm972qk07z71
+NarSize: 128
+References: "#;
+        let body = append_signature(unsigned, &sign_narinfo(&s, &keys, unsigned).unwrap());
+        assert!(check_nar(&body, nar.as_slice(), 64).unwrap().is_empty());
+        let trusted = TrustStore::parse("t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=").unwrap();
+        let checks = verify_narinfo(&s, &trusted, &body, &mut Warnings::new()).unwrap();
+        assert!(verified(&checks, false).unwrap());
+
+        // the hash and size are checked separately from the signature
+        let other = nar_of_file(b"goodbye, cruel world\n");
+        let mismatches = check_nar(&body, other.as_slice(), 64).unwrap();
+        assert_eq!(mismatches.len(), 2);
+        assert!(mismatches[0].starts_with("NarHash mismatch: narinfo has sha256:0nya2hjn"));
+        assert_eq!(
+            mismatches[1],
+            "NarSize mismatch: narinfo has 128, but the NAR is 136"
+        );
+    }
+
+    #[test]
+    fn test_sign_in_place() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let sig = "Sig: t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==";
+
+        let dir = std::env::temp_dir().join(format!(
+            "narinfo-tools-test-in-place-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [dir.join("a.narinfo"), dir.join("b.narinfo")];
+        for f in files.iter() {
+            std::fs::write(f, format!("{}\n", YOSYS)).unwrap();
+        }
+
+        for f in files.iter() {
+            let body = std::fs::read_to_string(f).unwrap();
+            assert!(sign_in_place(f, &body, &s, &keys).unwrap());
+        }
+        for f in files.iter() {
+            let body = std::fs::read_to_string(f).unwrap();
+            assert_eq!(body, format!("{}\n{}\n", YOSYS, sig));
+
+            // and signing again leaves it alone
+            assert!(!sign_in_place(f, &body, &s, &keys).unwrap());
+            assert_eq!(std::fs::read_to_string(f).unwrap(), body);
+        }
+        // a stale temporary file from an earlier run doesn't get in the way,
+        // and nothing else is left behind
+        std::fs::write(dir.join("a.narinfo.tmp"), "stale").unwrap();
+        let names = |dir: &std::path::Path| {
+            let mut names: Vec<_> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = names(&dir);
+        std::fs::write(&files[0], format!("{}\n", YOSYS)).unwrap();
+        assert!(sign_in_place(&files[0], YOSYS, &s, &keys).unwrap());
+        assert_eq!(names(&dir), before);
+
+        // the file keeps its mode, rather than getting the default one
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode =
+                |f: &std::path::Path| std::fs::metadata(f).unwrap().permissions().mode() & 0o777;
+            std::fs::write(&files[1], format!("{}\n", YOSYS)).unwrap();
+            std::fs::set_permissions(&files[1], std::fs::Permissions::from_mode(0o640)).unwrap();
+            assert!(sign_in_place(&files[1], YOSYS, &s, &keys).unwrap());
+            assert_eq!(mode(&files[1]), 0o640);
+
+            // if the original has gone away there's no mode to copy, and the
+            // copy doesn't get left behind
+            let gone = dir.join("gone.narinfo");
+            assert!(sign_in_place(&gone, YOSYS, &s, &keys).is_err());
+            assert!(!gone.exists());
+            assert_eq!(names(&dir), before);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
     #[test]
-    fn test_secretkey_to_keypair() {
-        let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
-        assert!(
-            sk_to_keypair(sk).is_ok(),
-            "keypair should have been created"
+    fn test_multi_store_validate_only_valid() {
+        let ms = MultiStore::parse("/nix/store:/chroot/nix/store").unwrap();
+
+        let invalid = YOSYS.replace("NarSize: 17680416", "NarSize: lots");
+        let records = [YOSYS, invalid.as_str()];
+        let valid: Vec<&str> = records
+            .iter()
+            .copied()
+            .filter(|r| ms.validate(r, &mut Warnings::new()).is_ok())
+            .collect();
+        assert_eq!(valid, vec![YOSYS]);
+
+        let elsewhere = YOSYS.replace("/nix/store/", "/gnu/store/");
+        let err = ms.validate(&elsewhere, &mut Warnings::new()).unwrap_err();
+        assert!(err.to_string().contains("is not in any known store"));
+    }
+
+    #[test]
+    fn test_narinfo_to_json_multiple_urls() {
+        let input = YOSYS.replace(
+            "URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz",
+            "URL: https://a.example/nar/06yc.nar.xz https://b.example/nar/06yc.nar.xz",
+        );
+
+        let mut output = String::new();
+        narinfo_to_json_with(
+            input,
+            &mut output,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["URL"],
+            serde_json::json!([
+                "https://a.example/nar/06yc.nar.xz",
+                "https://b.example/nar/06yc.nar.xz"
+            ])
+        );
+
+        let mut output = String::new();
+        narinfo_to_json_with(
+            YOSYS.to_string(),
+            &mut output,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["URL"],
+            "nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz"
         );
     }
 
     #[test]
-    fn test_sign_narinfo() {
-        let input = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
-URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
-Compression: xz
-FileHash: sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds
-FileSize: 3542408
-NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
-NarSize: 17680416
-References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib 20ix3np9v02ph8fwb2v41r5mzlfg8f73-libffi-3.4.2 9b9ryxskcwh573jwjz6m5l01whkcb39a-zlib-1.2.11 ab2ih3qiqkqjsapimxxyvzhxdwqcgyrn-tcl-8.6.11 dndi916j6yxzfzzj2sma2llhrlwahq06-bash-5.1-p16 dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15 fsq9kj579dnfygb12zcagbn1sg8dnl6d-protobuf-3.19.3 hb1lzaisgx2m9n29hqhh6yp6hasplq1v-python3-3.9.10 klq81kinj271cq5pfw995qchh3a42j0l-abc-verifier-2022.03.04 q29bwjibv9gi9n86203s38n0577w09sx-glibc-2.33-117 sxjqmj5vh2212isg67b33qzr3c1pdw2h-libffi-3.4.2-dev yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08
-Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv
-Sig: cache.nixos.org-1:eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA=="#;
+    fn test_check_max_references() {
+        assert!(check_max_references(YOSYS, 12).is_ok());
+        assert!(check_max_references(YOSYS, DEFAULT_MAX_REFERENCES).is_ok());
+
+        let err = check_max_references(YOSYS, 3).unwrap_err();
+        assert_eq!(err.to_string(), "too many references (more than 3)");
+    }
+
+    #[test]
+    fn test_check_max_line_length() {
+        use std::io::Read;
+
+        assert!(check_max_line_length(YOSYS, 1000).is_ok());
+
+        let input = format!("StorePath: /nix/store/foo\nGarbage: {}\n", "x".repeat(100));
+        let err = check_max_line_length(&input, 64).unwrap_err();
+        assert_eq!(err.to_string(), "line 2 is too long (more than 64 bytes)");
+
+        // reading stops at the long line, rather than reading all of it, so
+        // even one that never ends is fine
+        let err = read_chunked_limited(input.as_bytes(), 16, false, Some(64)).unwrap_err();
+        assert_eq!(err.to_string(), "line 2 is too long (more than 64 bytes)");
+        let endless = "StorePath: /nix/store/foo\r\n"
+            .as_bytes()
+            .chain(std::io::repeat(b'x'));
+        let err = read_chunked_limited(endless, 16, false, Some(64)).unwrap_err();
+        assert_eq!(err.to_string(), "line 2 is too long (more than 64 bytes)");
+        let crlf = "Garbage: xxxx\r\n".repeat(3);
+        assert_eq!(
+            read_chunked_limited(crlf.as_bytes(), 5, false, Some(13)).unwrap(),
+            crlf
+        );
+
+        let endless = "StorePath: /nix/store/foo\n"
+            .as_bytes()
+            .chain(std::io::repeat(b'x'));
+        let err = narinfos_to_ndjson(
+            std::io::BufReader::new(endless),
+            &mut Vec::new(),
+            &JsonOptions::default(),
+            false,
+            Some(64),
+            |_| Ok(()),
+            &mut Warnings::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "line 2 is too long (more than 64 bytes)");
+    }
+
+    #[test]
+    fn test_sign_base16_hash_keeps_body() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+
+        let base16 =
+            "NarHash: sha256:a342d864795cee832afd439af506723f95819a61162b94cabdb2103d2afefed6";
+        let input = YOSYS
+            .lines()
+            .filter(|l| !l.starts_with("Sig:"))
+            .map(|l| if l.starts_with("NarHash:") { base16 } else { l })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        // the signature is over the base32 form, i.e. the same as for yosys
+        assert_eq!(
+            fingerprint_narinfo(&s, &input).unwrap(),
+            fingerprint_narinfo(&s, YOSYS).unwrap()
+        );
+
+        let dir =
+            std::env::temp_dir().join(format!("narinfo-tools-test-base16-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("yosys.narinfo");
+        std::fs::write(&file, &input).unwrap();
+        assert!(sign_in_place(&file, &input, &s, &keys).unwrap());
+
+        let signed = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(find_field(&signed, "NarHash"), Some(&base16[9..]));
+        assert_eq!(
+            find_field(&signed, "Sig"),
+            Some("t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_narinfo_embedded_keys() {
+        let s = Store::new("/nix/store").unwrap();
+        let bundle = format!(
+            "{}\n# trusted-keys: t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U= {}\n",
+            YOSYS, CACHE_NIXOS_ORG_PK
+        );
+
+        let mut trusted = TrustStore::new();
+        trusted.add_embedded(&bundle).unwrap();
+        assert_eq!(trusted.keys_for("t").len(), 1);
+
+        let checks = verify_narinfo(&s, &trusted, &bundle, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Valid);
+        assert!(verified(&checks, false).unwrap());
+    }
+
+    #[test]
+    fn test_same_public_key() {
+        let pk = "t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=";
+        let renamed = "other-1:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=";
+        assert!(same_public_key(pk, renamed).unwrap());
+        assert!(!same_public_key(pk, CACHE_NIXOS_ORG_PK).unwrap());
+        assert!(same_public_key(pk, "t:nope").is_err());
+    }
+
+    #[test]
+    fn test_narinfo_to_json_content_addressed() {
+        let opts = JsonOptions {
+            content_addressed: true,
+            ..JsonOptions::default()
+        };
+        let json = |input: String| {
+            let mut output = String::new();
+            narinfo_to_json_with(input, &mut output, &opts, &mut Warnings::new()).unwrap();
+            serde_json::from_str::<serde_json::Value>(&output).unwrap()
+        };
+
+        assert_eq!(json(YOSYS.to_string())["ContentAddressed"], false);
+
+        let ca = format!(
+            "{}\nCA: fixed:r:sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3",
+            YOSYS
+        );
+        assert_eq!(json(ca)["ContentAddressed"], true);
+    }
+
+    #[test]
+    fn test_narinfo_to_json_unknown_deriver() {
+        let json = |input: &str| {
+            let mut output = String::new();
+            narinfo_to_json(input.to_string(), &mut output);
+            serde_json::from_str::<serde_json::Value>(&output).unwrap()
+        };
+
+        assert_eq!(
+            json(YOSYS)["Deriver"],
+            "x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv"
+        );
+
+        let input = YOSYS.replace(
+            "Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv",
+            "Deriver: unknown-deriver",
+        );
+        let output = json(&input);
+        assert!(output["Deriver"].is_null());
+
+        let info = json_to_narinfo(&output.to_string()).unwrap();
+        assert_eq!(info, input.parse::<NarInfo>().unwrap());
+    }
+
+    #[test]
+    fn test_extra_hash_fields() {
+        let sha512 = "sha512:0n7bh8dq0wrjlvqbdi4cwc6m2q4bkcnjg6ns0cd5r4pr9rpzl8gnn8x5q7sxdqsbzy1ww2nhx3a4ma7piazvjcvhb3cm1df9xjaz5a4";
+        let input = format!("{}\nNarHashSha512: {}", YOSYS, sha512);
+
+        let mut output = String::new();
+        narinfo_to_json(input.clone(), &mut output);
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["NarHashSha512"],
+            serde_json::json!({ "type": "sha512", "hash": &sha512[7..] })
+        );
+
+        // and it goes back again
+        let info = json_to_narinfo(&output).unwrap();
+        assert_eq!(
+            info.extra.get("NarHashSha512").map(|h| h.as_str()),
+            Some(sha512)
+        );
+
+        // the extra hash isn't signed, so cache.nixos.org's signature is fine
+        let s = Store::new("/nix/store").unwrap();
+        assert_eq!(
+            fingerprint_narinfo(&s, &input).unwrap(),
+            fingerprint_narinfo(&s, YOSYS).unwrap()
+        );
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+        let checks = verify_narinfo(&s, &trusted, &input, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Valid);
+    }
+
+    #[test]
+    fn test_narinfo_to_json_omit_empty() {
+        let refs = find_field(YOSYS, "References").unwrap();
+        let input = YOSYS
+            .replace("Compression: xz", "Compression:")
+            .replace(refs, "");
+        let json = |opts: &JsonOptions| {
+            let mut output = String::new();
+            narinfo_to_json_with(input.clone(), &mut output, opts, &mut Warnings::new()).unwrap();
+            serde_json::from_str::<serde_json::Value>(&output).unwrap()
+        };
+
+        let all = json(&JsonOptions::default());
+        assert_eq!(all["Compression"], "");
+        assert_eq!(all["References"], serde_json::json!([]));
+
+        for no_special_cases in [false, true] {
+            let omitted = json(&JsonOptions {
+                omit_empty: true,
+                no_special_cases,
+                ..JsonOptions::default()
+            });
+            let omitted = omitted.as_object().unwrap();
+            assert!(!omitted.contains_key("Compression"));
+            assert!(!omitted.contains_key("References"));
+            assert!(omitted.contains_key("NarSize"));
+        }
+    }
+
+    #[test]
+    fn test_json_to_narinfo_synthesize_url() {
+        let mut json: serde_json::Value = {
+            let mut output = String::new();
+            narinfo_to_json(YOSYS.to_string(), &mut output);
+            serde_json::from_str(&output).unwrap()
+        };
+        json.as_object_mut().unwrap().remove("URL");
+        let json = json.to_string();
+
+        let err = json_to_narinfo(&json).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::MissingField {
+                name: "URL".to_string()
+            })
+        );
+
+        let info = json_to_narinfo_with(&json, true).unwrap();
+        assert_eq!(
+            info.url,
+            "nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz"
+        );
+        assert_eq!(info, YOSYS.parse::<NarInfo>().unwrap());
+
+        let unknown = json.replace(r#""Compression":"xz""#, r#""Compression":"lzma""#);
+        assert_ne!(unknown, json);
+        assert!(json_to_narinfo_with(&unknown, true).is_err());
+    }
+
+    #[test]
+    fn test_narinfo_to_json_ca() {
+        let input = format!(
+            "{}\nCA: fixed:r:sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3",
+            YOSYS
+        );
+        let mut output = String::new();
+        narinfo_to_json(input.clone(), &mut output);
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["CA"],
+            serde_json::json!({
+                "method": "fixed:r:sha256",
+                "hash": "1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3",
+            })
+        );
+
+        // and it goes back to the same line
+        let info = json_to_narinfo(&output).unwrap();
+        assert_eq!(info, input.parse::<NarInfo>().unwrap());
+    }
+
+    #[test]
+    fn test_check_self_reference() {
+        let s = Store::new("/nix/store").unwrap();
+
+        let mut warnings = Warnings::new();
+        check_self_reference(&s, YOSYS, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+
+        let input = YOSYS.replace(" dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15 ", " ");
+        let mut warnings = Warnings::new();
+        check_self_reference(&s, &input, &mut warnings).unwrap();
+        assert_eq!(
+            warnings.messages(),
+            ["References don't include the path itself: dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15"]
+        );
+    }
+
+    #[test]
+    fn test_narinfo_to_json_group_sigs() {
+        let s = Store::new("/nix/store").unwrap();
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+        let opts = JsonOptions {
+            group_sigs: Some((&s, &trusted)),
+            ..JsonOptions::default()
+        };
+
+        let input = format!(
+            "{}\nSig: t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==",
+            YOSYS
+        );
+        let mut output = String::new();
+        narinfo_to_json_with(input, &mut output, &opts, &mut Warnings::new()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["Sig"],
+            serde_json::json!({
+                "valid": {
+                    "cache.nixos.org-1": "eJOBiYS+WArV7TmZbAwScAHSzRgYOmbaxk9MWexAYAx3x7g5UyP+xoLxdiAgmfRPd1tFzUBrJehW96QfA4sYDA==",
+                },
+                "invalid": {
+                    "t": "DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_narinfo_to_json_many_references() {
+        let refs: Vec<String> = (0..10_000)
+            .map(|i| format!("{:032}-ref-{}", i, i))
+            .collect();
+        let input = format!(
+            "StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15\nReferences: {}\n",
+            refs.join(" ")
+        );
+
+        let start = std::time::Instant::now();
+        let mut output = String::new();
+        narinfo_to_json_with(
+            input,
+            &mut output,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+        // generous, since this is a debug build, but quadratic behaviour
+        // would still take far longer
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let array = json["References"].as_array().unwrap();
+        assert_eq!(array.len(), 10_000);
+        assert_eq!(array[9_999], refs[9_999]);
+    }
+
+    #[test]
+    fn test_narinfo_to_canonical_json() {
+        // the same narinfo, with the fields and references shuffled around
+        let mut lines: Vec<&str> = YOSYS.lines().collect();
+        lines.reverse();
+        let refs = find_field(YOSYS, "References").unwrap();
+        let mut shuffled: Vec<&str> = refs.split_whitespace().collect();
+        shuffled.reverse();
+        let shuffled = format!("References: {}", shuffled.join(" "));
+        let reordered: Vec<&str> = lines
+            .into_iter()
+            .map(|l| {
+                if l.starts_with("References:") {
+                    shuffled.as_str()
+                } else {
+                    l
+                }
+            })
+            .collect();
+        let reordered = reordered.join("\n");
+
+        let a = narinfo_to_canonical_json(YOSYS, &mut Warnings::new()).unwrap();
+        let b = narinfo_to_canonical_json(&reordered, &mut Warnings::new()).unwrap();
+        assert_eq!(a, b);
+        assert!(!a.contains(' '));
+        assert!(a.starts_with(r#"{"Compression":"xz","Deriver":"#));
+    }
+
+    #[test]
+    fn test_verify_narinfo_retired_key() {
+        let s = Store::new("/nix/store").unwrap();
+        let mut trusted =
+            TrustStore::parse("t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=").unwrap();
+        trusted.add_retired(CACHE_NIXOS_ORG_PK).unwrap();
+
+        let mut warnings = Warnings::new();
+        let checks = verify_narinfo(&s, &trusted, YOSYS, &mut warnings).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Retired);
+        assert_eq!(tally(&checks), (1, 0));
+        assert!(verified(&checks, false).unwrap());
+        assert_eq!(
+            warnings.messages(),
+            ["cache.nixos.org-1: signed by retired key"]
+        );
+    }
+
+    #[test]
+    fn test_signable_fields() {
+        let fields = signable_fields(YOSYS).unwrap();
+        let keys: Vec<&str> = fields
+            .lines()
+            .map(|l| l.split_once(':').unwrap().0)
+            .collect();
+        assert_eq!(keys, SIGNED_FIELDS);
+        assert!(fields.starts_with(
+            "StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15\nNarHash: sha256:1mpyzqm3"
+        ));
+
+        // a different URL doesn't change what gets signed
+        let moved = YOSYS.replace("URL: nar/", "URL: https://example.org/nar/");
+        assert_eq!(signable_fields(&moved).unwrap(), fields);
+
+        // a duplicated field shows the value that signing uses, i.e. the last
+        let s = Store::new("/nix/store").unwrap();
+        let duped = format!("{}\nNarSize: 1\nReferences: ", YOSYS);
+        let fields = signable_fields(&duped).unwrap();
+        assert!(fields.contains("\nNarSize: 1\n"));
+        assert!(fields.ends_with("\nReferences: \n"));
+        assert!(fingerprint_narinfo(&s, &duped)
+            .unwrap()
+            .to_string()
+            .ends_with(";1;"));
+
+        let missing = YOSYS.replace("NarSize", "XNarSize");
+        assert_eq!(
+            signable_fields(&missing)
+                .unwrap_err()
+                .downcast_ref::<ParseError>(),
+            Some(&ParseError::MissingField {
+                name: "NarSize".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_explain_fingerprint() {
+        let s = Store::new("/nix/store").unwrap();
+
+        // signing doesn't care what order the references are listed in
+        let refs = find_field(YOSYS, "References").unwrap();
+        let mut reversed: Vec<&str> = refs.split_whitespace().collect();
+        reversed.reverse();
+        let input = YOSYS.replace(refs, &reversed.join(" "));
+        assert_eq!(
+            fingerprint_narinfo(&s, &input).unwrap(),
+            fingerprint_narinfo(&s, YOSYS).unwrap()
+        );
+
+        let verbose = explain_fingerprint(&s, &input).unwrap();
+        let lines: Vec<&str> = verbose.lines().collect();
+        assert_eq!(lines[0], "store dir: /nix/store");
+        assert_eq!(lines[1], "references:");
+        assert_eq!(
+            lines[2],
+            "  /nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib"
+        );
+        assert_eq!(
+            lines[13],
+            "  /nix/store/yx1xvmzia0fd0pvlp7cxjdlvrsdkhkjj-readline-6.3p08"
+        );
+        assert_eq!(
+            lines[14],
+            format!("fingerprint: {}", fingerprint_narinfo(&s, YOSYS).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_narinfo_to_json_no_special_cases() {
+        let opts = JsonOptions {
+            no_special_cases: true,
+            ..JsonOptions::default()
+        };
+        let input = format!("{}\nSig: t:abc", YOSYS);
+        let mut output = String::new();
+        narinfo_to_json_with(input, &mut output, &opts, &mut Warnings::new()).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["FileHash"],
+            "sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds"
+        );
+        assert_eq!(json["References"], find_field(YOSYS, "References").unwrap());
+        assert_eq!(json["NarSize"], "17680416");
+        assert_eq!(json["Sig"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_narinfo_to_json_trailing_newline() {
+        for opts in [
+            JsonOptions::default(),
+            JsonOptions {
+                no_special_cases: true,
+                ..JsonOptions::default()
+            },
+        ] {
+            let mut output = String::new();
+            narinfo_to_json_with(YOSYS.to_string(), &mut output, &opts, &mut Warnings::new())
+                .unwrap();
+            assert!(output.ends_with(" }\n"));
+            assert_eq!(output.matches('\n').count(), 1);
+
+            let mut output = String::new();
+            narinfo_to_json_with("\n\n".to_string(), &mut output, &opts, &mut Warnings::new())
+                .unwrap();
+            assert_eq!(output, "");
+        }
+    }
+
+    #[test]
+    fn test_narinfo_to_json_lenient_bare_sig() {
+        let input = "StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15\nSig: DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==";
+
+        let mut warnings = Warnings::new();
+        let mut output = String::new();
+        narinfo_to_json_with(
+            input.to_string(),
+            &mut output,
+            &JsonOptions::default(),
+            &mut warnings,
+        )
+        .unwrap();
+        assert_eq!(warnings.messages().len(), 1);
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert!(json.get("Sig").is_none());
+
+        let opts = JsonOptions {
+            lenient: true,
+            ..JsonOptions::default()
+        };
+        let mut warnings = Warnings::new();
+        let mut output = String::new();
+        narinfo_to_json_with(input.to_string(), &mut output, &opts, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["Sig"][BARE_SIG_HOST],
+            "DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA=="
+        );
+    }
+
+    #[test]
+    fn test_narinfo_to_json_verification() {
+        let s = Store::new("/nix/store").unwrap();
+        let trusted = TrustStore::parse(CACHE_NIXOS_ORG_PK).unwrap();
+        let opts = JsonOptions {
+            verification: Some((&s, &trusted)),
+            ..JsonOptions::default()
+        };
+        let json = |input: &str| {
+            let mut output = String::new();
+            narinfo_to_json_with(input.to_string(), &mut output, &opts, &mut Warnings::new())
+                .unwrap();
+            serde_json::from_str::<serde_json::Value>(&output).unwrap()
+        };
+
+        assert_eq!(
+            json(YOSYS)["Verification"],
+            serde_json::json!({ "status": "valid", "by": "cache.nixos.org-1" })
+        );
+
+        let tampered = YOSYS.replace("17680416", "17680417");
+        assert_eq!(
+            json(&tampered)["Verification"],
+            serde_json::json!({ "status": "invalid", "by": null })
+        );
+
+        let unsigned: String = YOSYS
+            .lines()
+            .filter(|l| !l.starts_with("Sig:"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(json(&unsigned)["Verification"]["status"], "unsigned");
+    }
+
+    #[test]
+    fn test_narinfo_to_json_escaping() {
+        let input = format!(
+            "{}\nSystem: x86_64-\"linux\"\\ \u{1}",
+            YOSYS.replace(
+                "Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv",
+                "Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-\"yosys\"\\.drv"
+            )
+        );
+        let mut output = String::new();
+        narinfo_to_json_with(
+            input,
+            &mut output,
+            &JsonOptions::default(),
+            &mut Warnings::new(),
+        )
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json["Deriver"],
+            "x9kirzdbj1f4r50l71jvcc86il8r94xc-\"yosys\"\\.drv"
+        );
+        assert_eq!(json["System"], "x86_64-\"linux\"\\ \u{1}");
+    }
+
+    #[test]
+    fn test_narinfo_to_json_bad_number() {
+        let input = YOSYS.replace("NarSize: 17680416", "NarSize: lots");
+        let mut warnings = Warnings::new();
+        let mut output = String::new();
+        narinfo_to_json_with(input, &mut output, &JsonOptions::default(), &mut warnings).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(json["NarSize"], "lots");
+        assert_eq!(warnings.messages(), ["NarSize is not a number: lots"]);
+    }
 
+    #[test]
+    fn test_sign_then_verify() {
         let s = Store::new("/nix/store").unwrap();
         let sk = "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
         let keys = sk_to_keypair(sk).unwrap();
 
-        let sig = sign_narinfo(&s, &keys, input).unwrap();
-        assert_eq!("t:DWUrR00frjSmaW5lRGmLxQ4TptkggNxiqDtkfZsJcSfleCIT4Qaw+orizNxxnPmhpLOeVhws5BjPzBznzgzkCA==", sig);
+        let unsigned: String = YOSYS
+            .lines()
+            .filter(|l| !l.starts_with("Sig:"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let signed = format!(
+            "{}\nSig: {}",
+            unsigned,
+            sign_narinfo(&s, &keys, &unsigned).unwrap()
+        );
+
+        let trusted = TrustStore::parse(&sk_to_pk(sk).unwrap()).unwrap();
+        let checks = verify_narinfo(&s, &trusted, &signed, &mut Warnings::new()).unwrap();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].host, "t");
+        assert_eq!(checks[0].status, SigStatus::Valid);
+        assert!(verified(&checks, false).unwrap());
+
+        // and changing anything that gets signed breaks it
+        let tampered = signed.replace("NarSize: 17680416", "NarSize: 17680417");
+        let checks = verify_narinfo(&s, &trusted, &tampered, &mut Warnings::new()).unwrap();
+        assert_eq!(checks[0].status, SigStatus::Invalid);
+        assert!(!verified(&checks, false).unwrap());
+    }
+
+    #[test]
+    fn test_sign_and_verify_agree() {
+        let s = Store::new("/nix/store").unwrap();
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let trusted = TrustStore::parse("t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=").unwrap();
+        let unsigned: String = YOSYS
+            .lines()
+            .filter(|l| !l.starts_with("Sig:"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        let round_trip = |body: &str| -> anyhow::Result<SigStatus> {
+            let sig = sign_narinfo(&s, &keys, body)?;
+            let signed = append_signature(body, &sig);
+            let checks = verify_narinfo(&s, &trusted, &signed, &mut Warnings::new())?;
+            Ok(checks[0].status)
+        };
+
+        // the last References wins, both when signing and verifying
+        let doubled = format!(
+            "{}References: dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15\n",
+            unsigned
+        );
+        assert_eq!(round_trip(&doubled).unwrap(), SigStatus::Valid);
+        assert!(fingerprint_narinfo(&s, &doubled)
+            .unwrap()
+            .as_str()
+            .ends_with(";17680416;/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15"));
+
+        // without References, neither works
+        let no_refs = unsigned.replace("References:", "Referencez:");
+        let missing = ParseError::MissingField {
+            name: "References".to_string(),
+        };
+        let err = round_trip(&no_refs).unwrap_err();
+        assert_eq!(err.downcast_ref::<ParseError>(), Some(&missing));
+
+        // but a URL isn't needed to sign, and a broken Sig doesn't get in the way
+        let no_url = unsigned.replace("URL:", "Url:");
+        assert_eq!(round_trip(&no_url).unwrap(), SigStatus::Valid);
+        let broken_sig = format!("{}Sig: nonsense\n", unsigned);
+        sign_narinfo(&s, &keys, &broken_sig).unwrap();
+        let sigs = sign_narinfo_keeping_sigs(&s, &keys, &broken_sig).unwrap();
+        assert_eq!(sigs[0], "nonsense");
+    }
+
+    #[test]
+    fn test_sign_narinfo_custom_store() {
+        let keys = sk_to_keypair("t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==").unwrap();
+        let input = YOSYS.replace("/nix/store/", "/custom/store/");
+        let s = Store::from_store_path(find_field(&input, "StorePath").unwrap()).unwrap();
+        assert_eq!(s.store_path, "/custom/store");
+        let fp = fingerprint_narinfo(&s, &input).unwrap();
+        assert!(fp
+            .as_str()
+            .contains(";/custom/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib,"));
+        let sig = sign_narinfo(&s, &keys, &input).unwrap();
+
+        // references may also be absolute, as long as it's the same store
+        let absolute = input.replace(" dw2xrnys127", " /custom/store/dw2xrnys127");
+        assert_ne!(absolute, input);
+        assert_eq!(sign_narinfo(&s, &keys, &absolute).unwrap(), sig);
+
+        let mixed = input.replace(" dw2xrnys127", " /nix/store/dw2xrnys127");
+        let err = sign_narinfo(&s, &keys, &mixed).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("is not in the store /custom/store"));
+        assert!(fingerprint_narinfo(&s, &mixed).is_err());
     }
 }
@@ -0,0 +1,489 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: © 2022 Austin Seipp
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+const YOSYS: &str = r#"StorePath: /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+URL: nar/06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds.nar.xz
+Compression: xz
+FileHash: sha256:06yc663a4bsf4j76rwx97iz9lwy3fwmf8m2ck3in5bsyzvcyk0ds
+FileSize: 3542408
+NarHash: sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3
+NarSize: 17680416
+References: 18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15
+Deriver: x9kirzdbj1f4r50l71jvcc86il8r94xc-yosys-0.15.drv
+"#;
+
+const SECRET_KEY: &str =
+    "t:02b8uY8PDLI9lWvEEOnBulRlcGB7ATMNan/Rn61XdwpwD2pfgERF9TpUUuNBb5c6GwBRLV/niW78YUjrt2i71Q==";
+const PUBLIC_KEY: &str = "t:cA9qX4BERfU6VFLjQW+XOhsAUS1f54lu/GFI67dou9U=";
+
+/// The command to run the binary with some arguments, and the test keys and
+/// store dir in its environment.
+fn command(args: &[&str]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_narinfo-tools"));
+    cmd.args(args)
+        .env("NIX_STORE_DIR", "/nix/store")
+        .env("NIX_SIGNING_KEY", SECRET_KEY)
+        .env("NIX_TRUSTED_PUBLIC_KEYS", PUBLIC_KEY);
+    cmd
+}
+
+/// Run the binary with some arguments, feeding it `stdin`.
+fn run(args: &[&str], stdin: &str) -> Output {
+    output(command(args), stdin)
+}
+
+/// Run a command, feeding it `stdin`.
+fn output(mut cmd: Command, stdin: &str) -> Output {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // the binary may exit without reading stdin at all, e.g. when it's given
+    // a file instead, which closes the pipe
+    match child.stdin.take().unwrap().write_all(stdin.as_bytes()) {
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => panic!("{}", e),
+        _ => {}
+    }
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_file_argument() {
+    let dir = std::env::temp_dir().join(format!("narinfo-tools-test-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("yosys.narinfo");
+    std::fs::write(&file, YOSYS).unwrap();
+    let path = file.to_str().unwrap();
+
+    // reading the file gives the same result as piping it in
+    for mode in ["json", "sign"] {
+        let from_stdin = run(&[mode], YOSYS);
+        let from_file = run(&[mode, path], "");
+        assert!(from_file.status.success(), "{} {}", mode, path);
+        assert!(!from_file.stdout.is_empty());
+        assert_eq!(from_file.stdout, from_stdin.stdout);
+    }
+
+    let signed = String::from_utf8(run(&["sign", path], "").stdout).unwrap();
+    std::fs::write(&file, &signed).unwrap();
+    let verified = run(&["verify", path], "");
+    assert!(verified.status.success());
+    assert_eq!(String::from_utf8(verified.stdout).unwrap(), "t: valid\n");
+
+    let missing = dir.join("missing.narinfo");
+    assert!(!run(&["json", missing.to_str().unwrap()], YOSYS)
+        .status
+        .success());
+    assert!(!run(&["json", path, path], "").status.success());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_make_cache_info() {
+    let out = run(
+        &[
+            "make-cache-info",
+            "--store-dir",
+            "/gnu/store",
+            "--priority",
+            "40",
+            "--want-mass-query",
+        ],
+        "",
+    );
+    assert!(out.status.success());
+    let info = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(
+        info,
+        "StoreDir: /gnu/store\nWantMassQuery: 1\nPriority: 40\n"
+    );
+
+    // it reads back the same way as a cache's own nix-cache-info
+    let json = run(&["json"], &info);
+    assert!(json.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&json.stdout).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "StoreDir": "/gnu/store",
+            "WantMassQuery": true,
+            "Priority": 40,
+        })
+    );
+
+    let defaults = run(&["make-cache-info"], "");
+    assert_eq!(
+        String::from_utf8(defaults.stdout).unwrap(),
+        "StoreDir: /nix/store\nWantMassQuery: 0\n"
+    );
+
+    assert!(!run(&["make-cache-info", "--priority", "high"], "")
+        .status
+        .success());
+}
+
+#[test]
+fn test_resolve_ref_urls() {
+    let dir = std::env::temp_dir().join(format!(
+        "narinfo-tools-test-ref-urls-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let gcc = "StorePath: /nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib\nURL: nar/gcc.nar.xz\n";
+    std::fs::write(dir.join("18fz9jnhmfkzkh6p1iwwwng4i7x4rag7.narinfo"), gcc).unwrap();
+
+    let out = run(
+        &["json", "--resolve-ref-urls", dir.to_str().unwrap()],
+        YOSYS,
+    );
+    assert!(out.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(
+        json["References"],
+        serde_json::json!([
+            {
+                "hash": "18fz9jnhmfkzkh6p1iwwwng4i7x4rag7",
+                "name": "gcc-10.3.0-lib",
+                "url": "nar/gcc.nar.xz",
+            },
+            // yosys itself isn't in the cache
+            {
+                "hash": "dw2xrnys127khw71bjygg7hmny62243n",
+                "name": "yosys-0.15",
+            },
+        ])
+    );
+
+    // a reference can't be used to read narinfos from outside of the dir
+    let secret = dir.join("secret");
+    std::fs::create_dir_all(&secret).unwrap();
+    std::fs::write(secret.join("x.narinfo"), "URL: nar/secret.nar\n").unwrap();
+    let cache = dir.join("cache");
+    std::fs::create_dir_all(&cache).unwrap();
+    for r in [
+        "../secret/x-foo",
+        "/etc/passwd-foo",
+        "18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-",
+    ] {
+        let input = YOSYS.replace("References: ", &format!("References: {} ", r));
+        let out = run(
+            &["json", "--resolve-ref-urls", cache.to_str().unwrap()],
+            &input,
+        );
+        assert!(!out.status.success(), "{} was accepted", r);
+        assert!(String::from_utf8(out.stderr)
+            .unwrap()
+            .contains("invalid reference"));
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_strict_colon() {
+    let input = YOSYS.replace("StorePath: ", "StorePath :  ");
+    assert!(run(&["json"], &input).status.success());
+
+    let out = run(&["--strict-colon", "json"], &input);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("StorePath :  /nix/store/"));
+
+    assert!(run(&["--strict-colon", "json"], YOSYS).status.success());
+}
+
+#[test]
+fn test_fingerprint() {
+    let out = run(&["fingerprint"], YOSYS);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "1;/nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15;\
+         sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3;17680416;\
+         /nix/store/18fz9jnhmfkzkh6p1iwwwng4i7x4rag7-gcc-10.3.0-lib,\
+         /nix/store/dw2xrnys127khw71bjygg7hmny62243n-yosys-0.15\n"
+    );
+}
+
+#[test]
+fn test_require_fields() {
+    let out = run(
+        &[
+            "--require-fields",
+            "StorePath,NarHash,NarSize,References",
+            "json",
+        ],
+        YOSYS,
+    );
+    assert!(out.status.success());
+
+    let out = run(&["--require-fields", "StorePath,CA", "sign"], YOSYS);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("missing required field: CA"));
+
+    // json-batch checks every record, not just the first one
+    let ca = "CA: fixed:r:sha256:1mpyzqm3s45jpp598aqnc6d8359zf83gb6j3zlm87vjwg5jdhhm3\n";
+    let batch = format!("{}{}\n{}", YOSYS, ca, YOSYS);
+    let out = run(&["--require-fields", "StorePath", "json-batch"], &batch);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap().lines().count(), 2);
+
+    let out = run(&["--require-fields", "CA", "json-batch"], &batch);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("missing required field: CA"));
+}
+
+#[test]
+fn test_hash_nar() {
+    let out = run(&["--chunk-size", "5", "hash-nar"], "hello, world\n");
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "NarHash: sha256:0830jxydl86c7qf54zx9mvfn73vdsvfyksy44bvxnvd0c8vzjgw5\nNarSize: 13\n"
+    );
+}
+
+#[test]
+fn test_fields() {
+    let out = run(&["fields"], YOSYS);
+    assert!(out.status.success());
+    let checklist = String::from_utf8(out.stderr).unwrap();
+    assert!(checklist.contains("[x] StorePath\n"));
+    assert!(checklist.contains("[ ] CA\n"));
+
+    let trimmed: String = YOSYS
+        .lines()
+        .filter(|x| !x.starts_with("NarHash:"))
+        .map(|x| format!("{}\n", x))
+        .collect();
+    let out = run(&["fields", "--json"], &trimmed);
+    assert!(!out.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(report["fields"]["StorePath"], true);
+    assert_eq!(report["fields"]["NarHash"], false);
+    assert_eq!(report["missingRequired"], serde_json::json!(["NarHash"]));
+}
+
+#[test]
+fn test_verify_infer_store_dir() {
+    let signed = String::from_utf8(run(&["sign"], YOSYS).stdout).unwrap();
+
+    let mut cmd = command(&["verify"]);
+    cmd.env_remove("NIX_STORE_DIR");
+    assert!(!output(cmd, &signed).status.success());
+
+    let mut cmd = command(&["verify", "--infer-store-dir"]);
+    cmd.env_remove("NIX_STORE_DIR");
+    let out = output(cmd, &signed);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "t: valid\n");
+}
+
+#[test]
+fn test_key_digest() {
+    let out = run(&["key-digest"], "");
+    assert!(out.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "host": "t",
+            "publicKey": PUBLIC_KEY,
+            "secretKeyFingerprint": "sha256:1jh24raff8b9ixabf80m9p21qivam5v5mwxlzsdv5lxnmswvmnjz",
+        })
+    );
+    assert!(!String::from_utf8(out.stdout)
+        .unwrap()
+        .contains(&SECRET_KEY[2..]));
+}
+
+#[test]
+fn test_help_and_version() {
+    let help = run(&["--help"], "");
+    assert!(help.status.success());
+    let help = String::from_utf8(help.stdout).unwrap();
+    assert!(help.contains("Usage: narinfo-tools"));
+    assert!(help.contains("sk-to-pk"));
+
+    let sign = run(&["sign", "--help"], YOSYS);
+    assert!(sign.status.success());
+    let sign = String::from_utf8(sign.stdout).unwrap();
+    assert!(sign.starts_with("Sign a narinfo"));
+    // the mode's own flags, and the global ones that go with it
+    for flag in [
+        "--json",
+        "--keep-sigs",
+        "--in-place",
+        "--key-file",
+        "--store-dir",
+    ] {
+        assert!(
+            sign.contains(&format!("  {} ", flag)),
+            "{} is missing",
+            flag
+        );
+    }
+    assert!(!run(&["bogus", "--help"], "").status.success());
+}
+
+#[test]
+fn test_mode_help_matches_flags() {
+    // run somewhere that nothing can be written to by accident
+    let dir = std::env::temp_dir().join(format!(
+        "narinfo-tools-test-mode-help-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let run_in_dir = |args: &[&str]| {
+        let mut cmd = command(args);
+        cmd.current_dir(&dir);
+        let out = output(cmd, "");
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        // a debug build panics if a mode looks for a flag that its help
+        // doesn't list
+        assert!(!stderr.contains("panicked"), "{:?}: {}", args, stderr);
+        stderr
+    };
+
+    let modes = String::from_utf8(run(&["--list-modes"], "").stdout).unwrap();
+    for mode in modes.lines() {
+        // every flag the mode looks for is listed
+        run_in_dir(&[mode]);
+
+        // and every flag that's listed is accepted
+        let help = String::from_utf8(run(&[mode, "--help"], "").stdout).unwrap();
+        let listed = help
+            .split("\nMode flags:\n")
+            .nth(1)
+            .map_or("", |rest| rest.split("\n\n").next().unwrap());
+        for line in listed.lines() {
+            let mut words = line.split_whitespace();
+            let flag = words.next().unwrap();
+            let args: Vec<&str> = match words.next() {
+                Some(v) if v.starts_with('<') => vec![mode, flag, "1"],
+                _ => vec![mode, flag],
+            };
+            let stderr = run_in_dir(&args);
+            assert!(
+                !stderr.contains("unknown flag") && !stderr.contains("requires a value"),
+                "{:?}: {}",
+                args,
+                stderr
+            );
+        }
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let version = run(&["--version"], "");
+    assert!(version.status.success());
+    assert_eq!(
+        String::from_utf8(version.stdout).unwrap(),
+        format!("narinfo-tools {}\n", env!("CARGO_PKG_VERSION"))
+    );
+
+    // and no mode at all is still an error, after the usage
+    let none = run(&[], "");
+    assert!(!none.status.success());
+    assert!(String::from_utf8(none.stderr)
+        .unwrap()
+        .contains("Usage: narinfo-tools"));
+}
+
+#[test]
+fn test_sign_with_several_keys() {
+    let generated = String::from_utf8(run(&["gen-key", "t-2"], "").stdout).unwrap();
+    let other = generated.lines().next().unwrap();
+
+    let mut cmd = command(&["sign"]);
+    cmd.env("NIX_SIGNING_KEY", format!("{}\n{}\n", SECRET_KEY, other));
+    let out = output(cmd, YOSYS);
+    assert!(out.status.success());
+    let signed = String::from_utf8(out.stdout).unwrap();
+    let sigs: Vec<&str> = signed.lines().filter(|l| l.starts_with("Sig: ")).collect();
+    assert_eq!(sigs.len(), 2);
+    assert!(sigs[0].starts_with("Sig: t:"));
+    assert!(sigs[1].starts_with("Sig: t-2:"));
+    assert!(signed.starts_with(YOSYS));
+}
+
+#[test]
+fn test_embedded_keys_need_opting_into() {
+    let generated = String::from_utf8(run(&["gen-key", "evil"], "").stdout).unwrap();
+    let (sk, pk) = (
+        generated.lines().next().unwrap(),
+        generated.lines().nth(1).unwrap(),
+    );
+
+    let mut cmd = command(&["sign"]);
+    cmd.env("NIX_SIGNING_KEY", sk);
+    let signed = String::from_utf8(output(cmd, YOSYS).stdout).unwrap();
+    let body = format!("{}# trusted-keys: {}\n", signed, pk);
+
+    let out = run(&["verify"], &body);
+    assert!(!out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "evil: untrusted key\n"
+    );
+
+    let out = run(&["verify", "--trust-embedded-keys"], &body);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "evil: valid\n");
+
+    // verify-batch goes by the same rule
+    let dir = std::env::temp_dir().join(format!(
+        "narinfo-tools-test-cli-embedded-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("yosys.narinfo");
+    std::fs::write(&file, &body).unwrap();
+    let file = file.to_str().unwrap();
+
+    assert!(!run(&["verify-batch", file], "").status.success());
+    assert!(run(&["verify-batch", "--trust-embedded-keys", file], "")
+        .status
+        .success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_max_references_json_batch() {
+    let batch = format!("{}\n{}", YOSYS, YOSYS);
+    assert!(run(&["json-batch"], &batch).status.success());
+
+    let out = run(&["--max-references", "1", "json-batch"], &batch);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("too many references (more than 1)"));
+}
+
+#[test]
+fn test_max_line_length() {
+    let long = format!("{}Garbage: {}\n", YOSYS, "x".repeat(500));
+    for mode in ["json", "json-batch"] {
+        assert!(run(&[mode], &long).status.success());
+        let out = run(&["--max-line-length", "200", mode], &long);
+        assert!(!out.status.success());
+        assert!(String::from_utf8(out.stderr)
+            .unwrap()
+            .contains("line 10 is too long (more than 200 bytes)"));
+    }
+}